@@ -0,0 +1,84 @@
+use crate::SimOptions;
+use anyhow::{bail, Result};
+use fast_boids::sim::{Settings, Simulation, StepTimings};
+use std::time::{Duration, Instant};
+
+/// Options of `fast_boids bench`
+#[derive(Debug, clap::Args)]
+pub struct Options {
+    /// Steps to time
+    #[arg(long, value_name = "N", default_value_t = 500)]
+    steps: usize,
+    #[command(flatten)]
+    sim: SimOptions,
+}
+
+type Phase = fn(&StepTimings) -> Duration;
+
+pub fn run(options: Options) -> Result<()> {
+    let steps = options.steps;
+    if steps == 0 {
+        bail!("--steps must be at least 1");
+    }
+    let settings = options.sim.settings(Settings::default())?;
+
+    println!(
+        "{} boids, depth {}, {} predators, {} steps",
+        settings.n_boids, settings.tree_depth, settings.num_predators, steps
+    );
+
+    let start = Instant::now();
+    let mut sim = Simulation::new(settings);
+    let setup = start.elapsed();
+
+    let mut timings = Vec::with_capacity(steps);
+    let mut totals = Vec::with_capacity(steps);
+    for _ in 0..steps {
+        let start = Instant::now();
+        sim.step()?;
+        totals.push(start.elapsed());
+        timings.push(sim.timings());
+    }
+
+    let phases: [(&str, Phase); 4] = [
+        ("select", |t| t.select),
+        ("reduce", |t| t.reduce),
+        ("motion", |t| t.motion),
+        ("predators", |t| t.predators),
+    ];
+
+    println!("setup      {:>10.3} ms", ms(setup));
+    println!(
+        "{:<10} {:>10} {:>10} {:>10} {:>7}",
+        "phase", "mean ms", "min ms", "max ms", "share"
+    );
+    let step_mean = mean(&totals);
+    for (name, phase) in phases.iter() {
+        let samples: Vec<Duration> = timings.iter().map(phase).collect();
+        print_row(name, &samples, step_mean);
+    }
+    print_row("step", &totals, step_mean);
+    println!("{:.1} steps per second", 1. / step_mean.as_secs_f64());
+
+    Ok(())
+}
+
+fn print_row(name: &str, samples: &[Duration], step_mean: Duration) {
+    let mean = mean(samples);
+    println!(
+        "{:<10} {:>10.3} {:>10.3} {:>10.3} {:>6.1}%",
+        name,
+        ms(mean),
+        ms(*samples.iter().min().unwrap()),
+        ms(*samples.iter().max().unwrap()),
+        100. * mean.as_secs_f64() / step_mean.as_secs_f64()
+    );
+}
+
+fn mean(samples: &[Duration]) -> Duration {
+    samples.iter().sum::<Duration>() / samples.len() as u32
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1e3
+}
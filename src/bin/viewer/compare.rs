@@ -0,0 +1,73 @@
+use crate::SimOptions;
+use anyhow::{Context, Result};
+use fast_boids::sim::{self, Settings, Simulation};
+use std::fs::File;
+use std::io::Write;
+
+/// Options of `fast_boids compare`
+#[derive(Debug, clap::Args)]
+pub struct Options {
+    /// Steps to run
+    #[arg(long, value_name = "N", default_value_t = 500)]
+    steps: usize,
+    /// CSV to write
+    #[arg(long, value_name = "FILE", default_value = "compare.csv")]
+    out: String,
+    // The flock defaults to 1024 boids here, since the reference is quadratic in it
+    #[command(flatten)]
+    sim: SimOptions,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let Options { steps, out, sim } = options;
+    let mut settings = sim.settings(Settings {
+        n_boids: 1024,
+        ..Settings::default()
+    })?;
+
+    // Predators steer randomly and noise is random by design, either of which would make the two
+    // runs diverge for reasons unrelated to the tree
+    settings.num_predators = 0;
+    settings.noise = 0.;
+
+    let mut sim = Simulation::new(settings.clone());
+    let mut reference = sim.boids().to_vec();
+
+    let mut csv = File::create(&out).with_context(|| format!("Creating {}", out))?;
+    writeln!(
+        csv,
+        "step,mean_position_error,tree_polarization,reference_polarization"
+    )?;
+
+    for step in 0..steps {
+        sim.step()?;
+        sim::brute_force_step(&mut reference, &settings);
+
+        let error: f32 = sim
+            .boids()
+            .iter()
+            .zip(&reference)
+            .map(|(a, b)| (a.pos - b.pos).magnitude())
+            .sum::<f32>()
+            / reference.len() as f32;
+        let tree_polarization = sim.polarization();
+        let reference_polarization = sim::polarization(&reference);
+
+        writeln!(
+            csv,
+            "{},{},{},{}",
+            step, error, tree_polarization, reference_polarization
+        )?;
+        if (step + 1) % 100 == 0 {
+            println!(
+                "Step {}: mean position error {:.3}, polarization {:.3} vs {:.3}",
+                step + 1,
+                error,
+                tree_polarization,
+                reference_polarization
+            );
+        }
+    }
+
+    Ok(())
+}
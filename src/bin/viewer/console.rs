@@ -0,0 +1,165 @@
+use crate::palette::ColorMode;
+use crate::{Follow, ShowPlanes};
+use anyhow::{bail, Result};
+use fast_boids::events::Event;
+use fast_boids::sim::Attractor;
+use nalgebra::Vector3;
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver};
+
+pub const HELP: &str = "Commands:
+    scatter STRENGTH    Turn every boid part of the way towards a random direction
+    set NAME VALUE      Change a weight
+    weights             Print every weight of the main simulation
+    preset NAME         Switch to a preset from the --settings files
+    preset next         Switch to the preset after the current one
+    split K             Send the flock off in K directions
+    boids N             Grow or shrink the flock to N boids, or by N with +N or -N
+    attract X,Y,Z S     Add an attractor pulling the flock with strength S
+    attract clear       Remove all attractors
+    reset               Respawn the flock with the current settings
+    pause               Stop or restart stepping; the view keeps drawing
+    step                Advance one step while paused
+    seek N              Go back to stored step N, 0 the oldest, kept with --history-frames
+    back                Go back one stored step
+    color MODE          Switch to color mode plain, gradient, species, group or velocity
+    view turn X,Y,Z     Turn the view by Euler angles in degrees
+    view move X,Y,Z     Move the rendered flock
+    view zoom S         Scale the rendered flock by S
+    view reset          Go back to the view from the command line
+    follow TARGET       Keep the view on the centroid, on a boid index, or off
+    pick X,Y,Z          Select the boid nearest a point to highlight and log, like --inspect
+    pick off            Clear the selection
+    planes LEVELS       Draw partition planes: none, all, or one tree level's
+    snapshot FILE       Write the main flock's positions and headings to a CSV
+    save FILE           Checkpoint the main simulation, settings and all
+    load FILE           Replace the main simulation with one saved by save
+    metrics             Print the main flock's polarization and group count
+    help                Show this list";
+
+/// A line typed into the console
+#[derive(Debug)]
+pub enum Command {
+    /// Anything an event timeline can do
+    Event(Event),
+    /// Add an attractor, or clear them all with `None`
+    Attract(Option<Attractor>),
+    Reset,
+    Pause,
+    /// Advance one step while paused
+    Step,
+    /// Restore a step kept by `history_frames`, 0 being the oldest
+    Seek(usize),
+    Back,
+    Color(ColorMode),
+    Planes(ShowPlanes),
+    View(ViewChange),
+    Follow(Option<Follow>),
+    /// Select the boid nearest a point, or clear the selection with `None`
+    Pick(Option<Vector3<f32>>),
+    Snapshot(String),
+    /// Checkpoint the main simulation with `Simulation::save_state()`
+    Save(String),
+    Load(String),
+    Metrics,
+    Weights,
+    /// Switch to a named preset, or the next one with `None`
+    Preset(Option<String>),
+}
+
+/// An adjustment to where the flock is drawn, see `WorldTransform`
+#[derive(Debug)]
+pub enum ViewChange {
+    /// Euler angles in degrees
+    Turn(Vector3<f32>),
+    Move(Vector3<f32>),
+    Zoom(f32),
+    Reset,
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Option<Self>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        Ok(Some(match words.as_slice() {
+            [] => return Ok(None),
+            ["attract", "clear"] => Command::Attract(None),
+            ["attract", pos, strength] => {
+                let strength: f32 = strength.parse()?;
+                if !strength.is_finite() {
+                    bail!("Attractor strength must be finite, got {}", strength);
+                }
+                Command::Attract(Some(Attractor {
+                    pos: crate::parse_vector(pos)?,
+                    strength,
+                }))
+            }
+            ["reset"] => Command::Reset,
+            ["pause"] => Command::Pause,
+            ["step"] => Command::Step,
+            ["seek", frame] => Command::Seek(frame.parse()?),
+            ["back"] => Command::Back,
+            ["color", mode] => Command::Color(ColorMode::parse(mode)?),
+            ["view", "turn", angles] => {
+                Command::View(ViewChange::Turn(crate::parse_vector(angles)?))
+            }
+            ["view", "move", offset] => {
+                Command::View(ViewChange::Move(crate::parse_vector(offset)?))
+            }
+            ["view", "zoom", factor] => {
+                let factor: f32 = factor.parse()?;
+                if !(factor > 0. && factor.is_finite()) {
+                    bail!("Zoom must be positive, got {}", factor);
+                }
+                Command::View(ViewChange::Zoom(factor))
+            }
+            ["view", "reset"] => Command::View(ViewChange::Reset),
+            ["follow", target] => Command::Follow(Follow::parse(target)?),
+            ["pick", "off"] => Command::Pick(None),
+            ["pick", point] => Command::Pick(Some(crate::parse_vector(point)?)),
+            ["planes", show] => Command::Planes(ShowPlanes::parse(show)?),
+            ["snapshot", path] => Command::Snapshot(path.to_string()),
+            ["save", path] => Command::Save(path.to_string()),
+            ["load", path] => Command::Load(path.to_string()),
+            ["metrics"] => Command::Metrics,
+            ["weights"] => Command::Weights,
+            ["preset", "next"] => Command::Preset(None),
+            ["preset", name] => Command::Preset(Some(name.to_string())),
+            ["help"] => {
+                println!("{}", HELP);
+                return Ok(None);
+            }
+            [first, ..] if ["scatter", "set", "split", "boids"].contains(first) => {
+                Command::Event(Event::parse(&words)?)
+            }
+            _ => bail!("Unknown command {:?}, try help", line),
+        }))
+    }
+}
+
+/// Read commands from stdin on a background thread, one per line, so they can be piped in from a
+/// file as well as typed. Bad lines are reported there and skipped; the frame loop only sees
+/// commands that parsed. The thread ends with stdin
+pub fn spawn() -> Receiver<Command> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    eprintln!("Console stopped: {}", e);
+                    return;
+                }
+            };
+            match Command::parse(&line) {
+                Ok(Some(command)) => {
+                    if tx.send(command).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => (),
+                Err(e) => eprintln!("{:#}", e),
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,82 @@
+use crate::Args;
+use anyhow::{bail, Result};
+use fast_boids::events::Timeline;
+use fast_boids::export::Exporter;
+use fast_boids::record::Recorder;
+use std::time::{Duration, Instant};
+
+/// Step the simulations without opening a window, for long or scripted runs on machines without
+/// a display. Only the options that don't draw anything take effect: scripted events, recording,
+/// exporting, stats and the fps cap. Runs for `--steps` steps if given, and otherwise until
+/// killed
+pub fn run(args: Args) -> Result<()> {
+    if args.replay.is_some() || args.console {
+        bail!("--replay and --console only work with a window, not with --headless");
+    }
+
+    let mut sims = crate::simulations(&args)?;
+    let mut timeline = match &args.events {
+        Some(path) => Timeline::load(path)?,
+        None => Timeline::default(),
+    };
+    let mut recorder = match &args.record {
+        Some(path) => Some(Recorder::create(path)?),
+        None => None,
+    };
+    let exporter = match args.export() {
+        Some((dir, format)) => Some(Exporter::new(dir, format)?),
+        None => None,
+    };
+
+    let mut last_step = Instant::now();
+    let mut last_stats = Instant::now();
+    for frame in 0..args.steps.unwrap_or(u32::MAX) {
+        if let Some(fps) = args.max_fps {
+            let period = Duration::from_secs_f32(1. / fps);
+            let elapsed = last_step.elapsed();
+            if elapsed < period {
+                std::thread::sleep(period - elapsed);
+            }
+        }
+        let now = Instant::now();
+        let step_time = now - last_step;
+        last_step = now;
+
+        for (frame, event) in timeline.due(frame) {
+            println!("Frame {}: {:?}", frame, event);
+            for sim in &mut sims {
+                event.apply(sim)?;
+            }
+        }
+        for sim in &mut sims {
+            let respawned = sim.respawned();
+            sim.step_once()?;
+            if sim.respawned() > respawned {
+                println!(
+                    "{} boids respawned after going non-finite",
+                    sim.respawned() - respawned
+                );
+            }
+        }
+        if args.dump_tree && frame == 0 {
+            print!("{}", sims[0].dump_tree());
+        }
+
+        if let Some(recorder) = &mut recorder {
+            recorder.record(&sims[0])?;
+        }
+        if let Some(exporter) = &exporter {
+            exporter.export(frame, sims[0].boids())?;
+        }
+
+        if let Some(interval) = args.stats_interval {
+            if last_stats.elapsed() >= interval {
+                last_stats = Instant::now();
+                for (idx, sim) in sims.iter().enumerate() {
+                    println!("{}", crate::stats_line(idx, step_time, &sim.stats()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1714 @@
+mod bench;
+mod compare;
+mod console;
+mod headless;
+mod model;
+mod palette;
+mod selftest;
+mod sweep;
+mod trails;
+use console::{Command, ViewChange};
+use fast_boids::events::{Event, Timeline};
+use fast_boids::export::{self, ExportFormat, Exporter};
+use fast_boids::record::{Recorder, Replay};
+use fast_boids::schema;
+use fast_boids::sim::{
+    Accel, Attractor, Plane, Settings, Shape, Simulation, Species, SplitNormal, SplitStrategy,
+    StepStats,
+};
+use model::{BoidModel, BoidShape};
+use palette::{ColorMode, Gradient, Palette};
+use trails::Trails;
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use klystron::{
+    runtime_3d::{launch, App},
+    DrawType, Engine, FramePacket, Material, Matrix4, Mesh, Object, Vertex, UNLIT_FRAG, UNLIT_VERT,
+};
+use nalgebra::{UnitQuaternion, Vector3};
+use std::collections::HashMap;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Distance between the origins of side-by-side simulations
+const SIM_SPACING: f32 = 30.;
+
+/// Length of a drawn force line per unit of force
+const FORCE_SCALE: f32 = 20.;
+
+/// Placement of the whole flock in the rendered scene. Only drawing is affected; the simulation
+/// keeps working in its own coordinates around the origin
+#[derive(Debug, Clone)]
+struct WorldTransform {
+    position: Vector3<f32>,
+    rotation: UnitQuaternion<f32>,
+    scale: f32,
+}
+
+impl Default for WorldTransform {
+    fn default() -> Self {
+        Self {
+            position: Vector3::zeros(),
+            rotation: UnitQuaternion::identity(),
+            scale: 1.,
+        }
+    }
+}
+
+impl WorldTransform {
+    /// Rotation from Euler angles in degrees
+    fn rotation(degrees: Vector3<f32>) -> UnitQuaternion<f32> {
+        UnitQuaternion::from_euler_angles(
+            degrees.x.to_radians(),
+            degrees.y.to_radians(),
+            degrees.z.to_radians(),
+        )
+    }
+
+    fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.position)
+            * self.rotation.to_homogeneous()
+            * Matrix4::new_scaling(self.scale)
+    }
+}
+
+/// Half the side of the root's drawn partition plane. Each level down is drawn smaller
+const PLANE_SIZE: f32 = 10.;
+
+/// Which partition planes are drawn
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ShowPlanes {
+    None,
+    All,
+    /// Only the groups this many levels below the root, whose planes split them into the groups
+    /// of the next level. Leaves aren't split, so their planes are only placed at their centroid
+    Level(u32),
+}
+
+impl ShowPlanes {
+    fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Self::None,
+            "all" => Self::All,
+            level => match level.parse() {
+                Ok(level) => Self::Level(level),
+                Err(_) => bail!("Expected none, all or a tree level, got {:?}", level),
+            },
+        })
+    }
+
+    fn shows(self, level: u32) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::Level(shown) => level == shown,
+        }
+    }
+}
+
+/// What the view keeps centered, see `--follow`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Follow {
+    Centroid,
+    Boid(usize),
+}
+
+impl Follow {
+    /// None for `off`
+    fn parse(s: &str) -> Result<Option<Self>> {
+        Ok(match s {
+            "off" => None,
+            "centroid" => Some(Self::Centroid),
+            index => match index.parse() {
+                Ok(index) => Some(Self::Boid(index)),
+                Err(_) => bail!("Expected off, centroid or a boid index, got {:?}", index),
+            },
+        })
+    }
+}
+
+/// How often to look at the settings file's modification time
+const RELOAD_INTERVAL: Duration = Duration::from_millis(500);
+
+struct Reload {
+    path: String,
+    /// Options as given, with `--preset` following the console's `preset`
+    command_line: Vec<String>,
+    /// Preset names found in the settings files, in the order they were written
+    presets: Vec<String>,
+    modified: SystemTime,
+    last_check: Instant,
+}
+
+struct MyApp {
+    lines_material: Material,
+    /// The main simulation followed by any `--compare` variants, laid out along +X
+    sims: Vec<Simulation>,
+    /// Draws `boid_mesh`, `inspect_mesh` and the palette's meshes, in lines or triangles
+    /// following `--boid-shape` and `--boid-mesh`
+    boid_material: Material,
+    boid_scale: f32,
+    boid_mesh: Mesh,
+    inspect_mesh: Mesh,
+    predator_mesh: Mesh,
+    force_mesh: Mesh,
+    attractor_mesh: Mesh,
+    /// Wireframes of the obstacles, and of the bounds with `--show-bounds`, already in
+    /// simulation coordinates
+    obstacle_meshes: Vec<Mesh>,
+    show_bounds: bool,
+    show_planes: ShowPlanes,
+    plane_color: [f32; 3],
+    /// Plane outlines by level and tree depth, created on first use
+    plane_meshes: HashMap<(u32, u32), Mesh>,
+    palette: Palette,
+    color_mode: ColorMode,
+    front_color: [f32; 3],
+    back_color: [f32; 3],
+    inspect: Option<usize>,
+    dump_tree: bool,
+    /// Applied to every simulation as their frames come up
+    timeline: Timeline,
+    /// Commands read from stdin with `--console`
+    console: Option<Receiver<Command>>,
+    /// Set by the console's `step`: advance one frame even though the simulations are paused
+    step_pending: bool,
+    /// Watches the `--settings` file
+    reload: Option<Reload>,
+    /// Writes every frame of the main simulation with `--record`
+    recorder: Option<Recorder>,
+    /// Frames shown in place of stepping with `--replay`, looping at the end
+    replay: Option<Replay>,
+    /// Writes the main simulation's boids to a file per frame with `--export-ply`/`--export-csv`
+    exporter: Option<Exporter>,
+    /// Recent positions of the main simulation's boids with `--trails`
+    trails: Option<Trails>,
+    planes: Vec<Plane>,
+    /// Applied to every drawn object, see `WorldTransform`. Recomputed from `view` every frame
+    world: Matrix4<f32>,
+    /// Changed by the console's view commands and by `--orbit`
+    view: WorldTransform,
+    /// The view from the command line, for the console's `view reset`
+    start_view: WorldTransform,
+    /// Degrees per frame to spin the view about the vertical axis
+    orbit: f32,
+    follow: Option<Follow>,
+    /// Where the view sits relative to the followed point
+    follow_offset: Vector3<f32>,
+    follow_damping: f32,
+    /// Smoothed position of the followed point, None until there is one
+    follow_pos: Option<Vector3<f32>>,
+    frame: u32,
+    max_fps: Option<f32>,
+    last_frame: Instant,
+    /// How often to print `StepStats`, None for never
+    stats_interval: Option<Duration>,
+    last_stats: Instant,
+}
+
+fn point_towards(vec: Vector3<f32>) -> Matrix4<f32> {
+    let y = vec.normalize();
+    let x = y.cross(&Vector3::y()).normalize();
+    let z = x.cross(&y).normalize();
+    nalgebra::Matrix3::from_columns(&[x, y, z]).to_homogeneous()
+}
+
+impl MyApp {
+    /// Apply the settings file if it changed since it was last read. Re-reads the whole command
+    /// line so that options given after `--settings` still win
+    fn reload_settings(&mut self, engine: &mut dyn Engine) -> Result<()> {
+        let reload = match &mut self.reload {
+            Some(reload) if reload.last_check.elapsed() >= RELOAD_INTERVAL => reload,
+            _ => return Ok(()),
+        };
+        reload.last_check = Instant::now();
+
+        let modified = std::fs::metadata(&reload.path)?.modified()?;
+        if modified == reload.modified {
+            return Ok(());
+        }
+        reload.modified = modified;
+
+        let args = Args::parse(&reload.command_line)?;
+        reload.presets = args.presets.clone();
+        let path = reload.path.clone();
+        self.apply_settings(engine, &args)?;
+        println!("Reloaded settings from {}", path);
+        Ok(())
+    }
+
+    /// Switch to the settings file's preset `name`, or the one after the current preset with None
+    fn switch_preset(&mut self, engine: &mut dyn Engine, name: Option<String>) -> Result<()> {
+        let reload = match &mut self.reload {
+            Some(reload) if !reload.presets.is_empty() => reload,
+            _ => bail!("No presets, they are read from [NAME] sections of --settings files"),
+        };
+        let current = preset_named(&reload.command_line);
+        let name = match name {
+            Some(name) => name,
+            None => {
+                let idx = current.and_then(|c| reload.presets.iter().position(|p| *p == c));
+                let next = idx.map_or(0, |idx| (idx + 1) % reload.presets.len());
+                reload.presets[next].clone()
+            }
+        };
+
+        let mut command_line = without_preset(&reload.command_line);
+        command_line.extend(["--preset".to_string(), name.clone()]);
+        let args = Args::parse(&command_line)?;
+        reload.command_line = command_line;
+        self.apply_settings(engine, &args)?;
+        println!("Switched to preset {}", name);
+        Ok(())
+    }
+
+    // Give every simulation the settings from `args`, keeping their comparison weights
+    fn apply_settings(&mut self, engine: &mut dyn Engine, args: &Args) -> Result<()> {
+        for (idx, sim) in self.sims.iter_mut().enumerate() {
+            let mut settings = args.settings.clone();
+            if let Some((name, value)) = idx.checked_sub(1).and_then(|i| args.compare.get(i)) {
+                settings.set_weight(name, *value)?;
+            }
+            sim.update_settings(settings)?;
+        }
+        self.rebuild_obstacles(engine, &args.settings)
+    }
+
+    // Replace the obstacle wireframes with those of `settings`, freeing the old meshes
+    fn rebuild_obstacles(&mut self, engine: &mut dyn Engine, settings: &Settings) -> Result<()> {
+        for mesh in self.obstacle_meshes.drain(..) {
+            engine.remove_mesh(mesh)?;
+        }
+        self.obstacle_meshes = obstacle_meshes(engine, settings, self.show_bounds)?;
+        Ok(())
+    }
+
+    fn run_command(&mut self, engine: &mut dyn Engine, command: Command) -> Result<()> {
+        match command {
+            Command::Event(event) => {
+                for sim in &mut self.sims {
+                    event.apply(sim)?;
+                }
+                if let Event::SetWeight(name, _) = &event {
+                    println!("{} = {}", name, self.sims[0].weight(name)?);
+                }
+            }
+            Command::Attract(attractor) => {
+                for sim in &mut self.sims {
+                    let mut attractors = sim.attractors().to_vec();
+                    match attractor {
+                        Some(attractor) => attractors.push(attractor),
+                        None => attractors.clear(),
+                    }
+                    sim.set_attractors(attractors)?;
+                }
+            }
+            Command::Reset => {
+                for sim in &mut self.sims {
+                    let paused = sim.is_paused();
+                    *sim = Simulation::new(sim.settings().clone());
+                    if paused {
+                        sim.pause();
+                    }
+                }
+            }
+            Command::Pause => {
+                let paused = !self.sims[0].is_paused();
+                for sim in &mut self.sims {
+                    match paused {
+                        true => sim.pause(),
+                        false => sim.resume(),
+                    }
+                }
+                println!("{}", if paused { "Paused" } else { "Resumed" });
+            }
+            Command::Step => match self.sims[0].is_paused() {
+                true => self.step_pending = true,
+                false => println!("Not paused, so already stepping"),
+            },
+            Command::Seek(frame) => {
+                for sim in &mut self.sims {
+                    sim.seek(frame)?;
+                }
+                let stored = self.sims[0].history_len();
+                println!("Showing stored step {} of {}", frame, stored);
+            }
+            Command::Back => {
+                for sim in &mut self.sims {
+                    sim.step_back()?;
+                }
+                println!("Went back one stored step");
+            }
+            Command::Snapshot(path) => {
+                export::write_boids(path.as_ref(), ExportFormat::Csv, self.sims[0].boids())?;
+                println!("Wrote {} boids to {}", self.sims[0].boids().len(), path);
+            }
+            Command::Save(path) => {
+                self.sims[0].save_state(&path)?;
+                println!("Saved frame {} to {}", self.sims[0].frame(), path);
+            }
+            Command::Load(path) => {
+                self.sims[0] = Simulation::load_state(&path)?;
+                let settings = self.sims[0].settings().clone();
+                self.rebuild_obstacles(engine, &settings)?;
+                println!("Loaded frame {} from {}", self.sims[0].frame(), path);
+            }
+            Command::Color(mode) => self.color_mode = mode,
+            Command::Planes(show) => self.show_planes = show,
+            Command::Pick(None) => self.inspect = None,
+            Command::Pick(Some(point)) => {
+                let sim = &self.sims[0];
+                if let Some((index, distance)) = sim.nearest_boid(point) {
+                    let boid = sim.boid(index)?;
+                    println!(
+                        "Picked boid {}, {:.3} away: pos {:?} heading {:?} speed {:.4} species {}",
+                        index,
+                        distance,
+                        boid.pos.as_slice(),
+                        boid.heading.as_slice(),
+                        boid.speed,
+                        boid.species
+                    );
+                    self.inspect = Some(index);
+                }
+            }
+            Command::Follow(follow) => {
+                self.follow = follow;
+                self.follow_pos = None;
+            }
+            Command::View(change) => match change {
+                ViewChange::Turn(degrees) => {
+                    self.view.rotation = WorldTransform::rotation(degrees) * self.view.rotation
+                }
+                ViewChange::Move(offset) => self.view.position += offset,
+                ViewChange::Zoom(factor) => self.view.scale *= factor,
+                ViewChange::Reset => self.view = self.start_view.clone(),
+            },
+            Command::Preset(name) => self.switch_preset(engine, name)?,
+            Command::Weights => {
+                for name in Settings::WEIGHTS {
+                    println!("{} = {}", name, self.sims[0].weight(name)?);
+                }
+            }
+            Command::Metrics => {
+                let sim = &self.sims[0];
+                println!(
+                    "Frame {}: {} boids, polarization {:.3}, {} groups, {} respawned",
+                    self.frame,
+                    sim.boids().len(),
+                    sim.polarization(),
+                    sim.groups().len(),
+                    sim.respawned()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl App for MyApp {
+    const NAME: &'static str = "Boids";
+
+    type Args = Args;
+
+    fn new(engine: &mut dyn Engine, args: Self::Args) -> Result<Self> {
+        let sims = simulations(&args)?;
+
+        let lines_material = engine.add_material(UNLIT_VERT, UNLIT_FRAG, DrawType::Lines)?;
+
+        let model = match &args.boid_mesh {
+            Some(path) => BoidModel::load_obj(path)?,
+            None => BoidModel::from_shape(args.boid_shape),
+        };
+        let boid_material = match model.draw_type() {
+            DrawType::Lines => lines_material,
+            draw_type => engine.add_material(UNLIT_VERT, UNLIT_FRAG, draw_type)?,
+        };
+        let boid_mesh = model.mesh(engine, args.boid_color, 1.)?;
+        let inspect_mesh = model.mesh(engine, [0., 1., 0.3], 3.)?;
+
+        let (vertices, indices) = boid([1., 0., 0.], 2.);
+        let predator_mesh = engine.add_mesh(&vertices, &indices)?;
+
+        let (vertices, indices) = boid([1., 0., 1.], 1.);
+        let force_mesh = engine.add_mesh(&vertices, &indices)?;
+
+        let (vertices, indices) = cross(0.5, [1., 1., 0.2]);
+        let attractor_mesh = engine.add_mesh(&vertices, &indices)?;
+
+        let obstacle_meshes = obstacle_meshes(engine, &args.settings, args.show_bounds)?;
+
+        Ok(Self {
+            show_planes: args.show_planes,
+            plane_color: args.plane_color,
+            plane_meshes: HashMap::new(),
+            sims,
+            planes: Vec::new(),
+            boid_mesh,
+            inspect_mesh,
+            predator_mesh,
+            force_mesh,
+            attractor_mesh,
+            obstacle_meshes,
+            show_bounds: args.show_bounds,
+            palette: Palette::new(model),
+            boid_material,
+            boid_scale: args.boid_scale,
+            color_mode: args.color_mode,
+            front_color: args.front_color,
+            back_color: args.back_color,
+            inspect: args.inspect,
+            dump_tree: args.dump_tree,
+            timeline: match &args.events {
+                Some(path) => Timeline::load(path)?,
+                None => Timeline::default(),
+            },
+            console: match args.console {
+                true => Some(console::spawn()),
+                false => None,
+            },
+            step_pending: false,
+            reload: match args.settings_file() {
+                Some(path) => Some(Reload {
+                    modified: std::fs::metadata(path)?.modified()?,
+                    path: path.clone(),
+                    command_line: args.command_line.clone(),
+                    presets: args.presets.clone(),
+                    last_check: Instant::now(),
+                }),
+                None => None,
+            },
+            recorder: match &args.record {
+                Some(path) => Some(Recorder::create(path)?),
+                None => None,
+            },
+            replay: match &args.replay {
+                Some(path) => Some(Replay::open(path)?),
+                None => None,
+            },
+            exporter: match args.export() {
+                Some((dir, format)) => Some(Exporter::new(dir, format)?),
+                None => None,
+            },
+            trails: match args.trails {
+                0 => None,
+                len => Some(Trails::new(len, args.boid_color)),
+            },
+            world: args.world().matrix(),
+            view: args.world(),
+            start_view: args.world(),
+            orbit: args.orbit,
+            follow: args.follow,
+            follow_offset: args.follow_offset,
+            follow_damping: args.follow_damping,
+            follow_pos: None,
+            lines_material,
+            frame: 0,
+            max_fps: args.max_fps,
+            last_frame: Instant::now(),
+            stats_interval: args.stats_interval,
+            last_stats: Instant::now(),
+        })
+    }
+
+    fn next_frame(&mut self, engine: &mut dyn Engine) -> Result<FramePacket> {
+        // Hold the loop at the fps cap, counting the time spent since the last frame began
+        if let Some(fps) = self.max_fps {
+            let period = Duration::from_secs_f32(1. / fps);
+            let elapsed = self.last_frame.elapsed();
+            if elapsed < period {
+                std::thread::sleep(period - elapsed);
+            }
+        }
+        let now = Instant::now();
+        let frame_time = now - self.last_frame;
+        self.last_frame = now;
+
+        let mut objects = Vec::new();
+
+        // A half-written file is likely to fail to parse, and the next save will fix it
+        if let Err(e) = self.reload_settings(engine) {
+            eprintln!("{:#}", e);
+        }
+
+        let commands: Vec<Command> = match &self.console {
+            Some(console) => console.try_iter().collect(),
+            None => Vec::new(),
+        };
+        for command in commands {
+            // A bad command shouldn't take the viewer down with it
+            if let Err(e) = self.run_command(engine, command) {
+                eprintln!("{:#}", e);
+            }
+        }
+
+        if self.orbit != 0. {
+            let spin = WorldTransform::rotation(Vector3::y() * self.orbit);
+            self.view.rotation = spin * self.view.rotation;
+        }
+        self.world = self.view.matrix();
+        if let Some(follow) = self.follow {
+            let target = match follow {
+                Follow::Centroid => self.sims[0].centroid(),
+                // The flock may have shrunk past the boid since following started
+                Follow::Boid(index) => match self.sims[0].boid(index) {
+                    Ok(boid) => Some(boid.pos),
+                    Err(e) => {
+                        println!("Stopped following: {:#}", e);
+                        self.follow = None;
+                        None
+                    }
+                },
+            };
+            if let Some(target) = target {
+                let pos = self.follow_pos.get_or_insert(target);
+                *pos += (target - *pos) * (1. - self.follow_damping);
+                self.world *= Matrix4::new_translation(&(-self.follow_offset - *pos));
+            }
+        }
+
+        // Drawing continues while paused, but nothing steps and the frame count holds
+        let stepping = !self.sims[0].is_paused() || std::mem::take(&mut self.step_pending);
+        if stepping {
+            if let Some(replay) = &mut self.replay {
+                // Nothing is simulated while replaying, so scripted events are left out too
+                let frame = match replay.next_frame()? {
+                    Some(frame) => frame,
+                    None => {
+                        replay.rewind()?;
+                        replay.next_frame()?.context("Recording has no frames")?
+                    }
+                };
+                self.sims[0].load_frame(&frame);
+                self.planes = frame.groups;
+            } else {
+                for (frame, event) in self.timeline.due(self.frame) {
+                    println!("Frame {}: {:?}", frame, event);
+                    for sim in &mut self.sims {
+                        event.apply(sim)?;
+                    }
+                }
+
+                for (idx, sim) in self.sims.iter_mut().enumerate() {
+                    let respawned = sim.respawned();
+                    let planes = sim.step_once()?;
+                    if sim.respawned() > respawned {
+                        println!(
+                            "{} boids respawned after going non-finite",
+                            sim.respawned() - respawned
+                        );
+                    }
+                    if idx == 0 {
+                        self.planes = planes;
+                    }
+                }
+
+                if self.dump_tree && self.frame == 0 {
+                    print!("{}", self.sims[0].dump_tree());
+                }
+            }
+
+            if let Some(recorder) = &mut self.recorder {
+                recorder.record(&self.sims[0])?;
+            }
+            if let Some(exporter) = &self.exporter {
+                exporter.export(self.frame, self.sims[0].boids())?;
+            }
+            if let Some(trails) = &mut self.trails {
+                trails.push(self.sims[0].boids());
+            }
+        }
+
+        let depth = self.sims[0].settings().tree_depth;
+        for group in &self.planes {
+            let level = 31 - (group.id + 1).leading_zeros();
+            if !self.show_planes.shows(level) {
+                continue;
+            }
+            let mesh = match self.plane_meshes.get(&(level, depth)) {
+                Some(mesh) => *mesh,
+                None => {
+                    // From the plane color at the root to white at the leaves
+                    let t = level as f32 / depth.max(1) as f32;
+                    let color = palette::lerp_color(self.plane_color, [1.; 3], t);
+                    let size = PLANE_SIZE * 0.75_f32.powi(level as i32);
+                    let (vertices, indices) = plane(size, color);
+                    let mesh = engine.add_mesh(&vertices, &indices)?;
+                    self.plane_meshes.insert((level, depth), mesh);
+                    mesh
+                }
+            };
+            objects.push(Object {
+                material: self.lines_material,
+                mesh,
+                transform: self.world
+                    * Matrix4::new_translation(&group.pos)
+                    * point_towards(group.normal),
+            });
+        }
+
+        // As with following, the flock may have shrunk past the inspected boid
+        let inspected = self.inspect.map(|index| (index, self.sims[0].boid(index)));
+        if let Some((_, Err(e))) = &inspected {
+            println!("Stopped inspecting: {:#}", e);
+            self.inspect = None;
+        }
+        if let Some((index, Ok(boid))) = inspected {
+            println!(
+                "Boid {}: pos {:?} heading {:?} level {} mask {:b}",
+                index,
+                boid.pos.as_slice(),
+                boid.heading.as_slice(),
+                boid.level(),
+                boid.mask()
+            );
+        }
+
+        if let Some(trails) = &mut self.trails {
+            for mesh in trails.meshes(engine)? {
+                objects.push(Object {
+                    material: self.lines_material,
+                    mesh: *mesh,
+                    transform: self.world,
+                });
+            }
+        }
+
+        for (sim_idx, sim) in self.sims.iter().enumerate() {
+            let offset = self.world
+                * Matrix4::new_translation(&(Vector3::x() * sim_idx as f32 * SIM_SPACING));
+
+            let gradient = match self.color_mode {
+                ColorMode::Gradient => Gradient::new(sim),
+                _ => None,
+            };
+            let fastest = sim.boids().iter().map(|b| b.speed).fold(0., f32::max);
+
+            for (idx, boid) in sim.boids().iter().enumerate() {
+                let mesh = if sim_idx == 0 && self.inspect == Some(idx) {
+                    self.inspect_mesh
+                } else if let Some(gradient) = &gradient {
+                    let t = gradient.t(&boid.pos);
+                    let color = palette::lerp_color(self.back_color, self.front_color, t);
+                    self.palette.mesh(engine, color)?
+                } else if self.color_mode == ColorMode::Species {
+                    let color = palette::species_color(boid.species);
+                    self.palette.mesh(engine, color)?
+                } else if self.color_mode == ColorMode::Group {
+                    let color = palette::group_color(boid.species, boid.group_id());
+                    self.palette.mesh(engine, color)?
+                } else if self.color_mode == ColorMode::Velocity {
+                    let color = palette::velocity_color(boid.heading, boid.speed, fastest);
+                    self.palette.mesh(engine, color)?
+                } else {
+                    self.boid_mesh
+                };
+                objects.push(Object {
+                    material: self.boid_material,
+                    mesh,
+                    transform: offset
+                        * Matrix4::new_translation(&boid.pos)
+                        * point_towards(boid.heading)
+                        * Matrix4::from_axis_angle(&Vector3::y_axis(), boid.roll)
+                        * Matrix4::new_scaling(self.boid_scale),
+                });
+            }
+
+            let forces = sim.forces().unwrap_or(&[]);
+            for (boid, force) in sim.boids().iter().zip(forces) {
+                let len = force.magnitude() * FORCE_SCALE;
+                if len > 1e-3 {
+                    objects.push(Object {
+                        material: self.lines_material,
+                        mesh: self.force_mesh,
+                        transform: offset
+                            * Matrix4::new_translation(&boid.pos)
+                            * point_towards(*force)
+                            * Matrix4::new_nonuniform_scaling(&Vector3::new(1., len, 1.)),
+                    });
+                }
+            }
+
+            for mesh in &self.obstacle_meshes {
+                objects.push(Object {
+                    material: self.lines_material,
+                    mesh: *mesh,
+                    transform: offset,
+                });
+            }
+
+            for attractor in sim.attractors() {
+                objects.push(Object {
+                    material: self.lines_material,
+                    mesh: self.attractor_mesh,
+                    transform: offset * Matrix4::new_translation(&attractor.pos),
+                });
+            }
+
+            for predator in sim.predators() {
+                objects.push(Object {
+                    material: self.lines_material,
+                    mesh: self.predator_mesh,
+                    transform: offset
+                        * Matrix4::new_translation(&predator.pos)
+                        * point_towards(predator.heading),
+                });
+            }
+        }
+
+        if let Some(interval) = self.stats_interval {
+            if self.last_stats.elapsed() >= interval {
+                self.last_stats = Instant::now();
+                for (idx, sim) in self.sims.iter().enumerate() {
+                    println!("{}", stats_line(idx, frame_time, &sim.stats()));
+                }
+            }
+        }
+
+        engine.update_time_value(self.frame as f32 / 100.)?;
+        if stepping {
+            self.frame += 1;
+        }
+
+        Ok(FramePacket { objects })
+    }
+}
+
+/// Flocking boids, sped up by steering each boid by groups from a partition tree rather than by
+/// every other boid. Without a subcommand, opens the viewer
+#[derive(Debug, Parser)]
+#[command(
+    name = "fast_boids",
+    args_conflicts_with_subcommands = true,
+    args_override_self = true,
+    allow_negative_numbers = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    tool: Option<Tool>,
+    #[command(flatten)]
+    args: Args,
+}
+
+impl Cli {
+    // Parse `command_line`, reading each `--settings` file in where it was given
+    fn parse(command_line: &[String]) -> Result<Self> {
+        let preset = preset_named(command_line);
+        let (expanded, presets) = expand_settings(command_line, preset.as_deref())?;
+        let program = std::iter::once("fast_boids".to_string());
+        let mut cli = Self::try_parse_from(program.chain(expanded))?;
+        if cli.tool.is_none() {
+            cli.args.resolve(command_line, presets)?;
+        }
+        Ok(cli)
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Tool {
+    /// Run a short simulation for every combination of the given weights and write the
+    /// polarization after the last step of each to a CSV. Every run is seeded with --seed, 0
+    /// unless given, so runs only differ in the swept weights
+    Sweep(sweep::Options),
+    /// Run a small simulation and check the invariants it should always keep, printing PASS or
+    /// FAIL for each
+    Selftest,
+    /// Run the tree-accelerated simulation next to a brute-force reference where every boid sees
+    /// every other boid, both from the same starting flock, and write how far they drift apart
+    /// each step to a CSV. Shows how much accuracy a given tree depth costs
+    Compare(compare::Options),
+    /// Run the simulation without opening a window and print how long each phase of a step
+    /// takes. Tree building is split into select, where boids are sorted to either side of each
+    /// plane, and reduce, where each side is summed into its group
+    #[command(long_flag = "bench")]
+    Bench(bench::Options),
+}
+
+/// Simulation options shared by the viewer and the tools. Options left out keep the defaults of
+/// whichever one is running
+#[derive(Debug, clap::Args)]
+#[command(next_help_heading = "Simulation")]
+struct SimOptions {
+    /// Start from the settings in FILE, a JSON object as --print-schema describes, in place of
+    /// the defaults. The other options still apply on top
+    #[arg(long, value_name = "FILE")]
+    settings_json: Option<String>,
+    /// Flock size. Defaults to 16384, or 4096 for sweep and 1024 for compare
+    #[arg(long, value_name = "N")]
+    boids: Option<usize>,
+    /// Partition tree depth. Defaults to 5
+    #[arg(long, value_name = "N")]
+    depth: Option<u32>,
+    /// Number of predators hunting the flock
+    #[arg(long, value_name = "N")]
+    predators: Option<usize>,
+    /// Seed the simulation so runs repeat exactly
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+    /// Set a simulation weight such as cohere, steer or parallel. May be repeated
+    #[arg(long = "set", value_name = "NAME=VALUE", value_parser = parse_weight)]
+    weights: Vec<(String, f32)>,
+    /// Steer each boid by only its K nearest leaf groups
+    #[arg(long, value_name = "K")]
+    max_neighbors: Option<usize>,
+    /// Steer each boid by its K nearest boids instead of by groups, looked for in the grid with
+    /// --accel grid and in the boid's own leaf otherwise
+    #[arg(long, value_name = "K")]
+    neighbors: Option<usize>,
+    /// Add a species that only flocks with its own kind, with comma-separated overrides such as
+    /// speed=0.06,cohere=2 and the rest taken from --set. May be repeated; the flock is divided
+    /// evenly between species
+    #[arg(long = "species", value_name = "WEIGHTS")]
+    species: Vec<String>,
+    /// Add a spherical obstacle. May be repeated
+    #[arg(
+        long = "sphere",
+        value_name = "X,Y,Z:R",
+        allow_hyphen_values = true,
+        value_parser = parse_sphere
+    )]
+    spheres: Vec<Shape>,
+    /// Add a box obstacle between two corners. May be repeated
+    #[arg(
+        long = "box",
+        value_name = "X,Y,Z:X,Y,Z",
+        allow_hyphen_values = true,
+        value_parser = parse_box
+    )]
+    boxes: Vec<Shape>,
+    /// Turn boids back when they leave this sphere
+    #[arg(
+        long,
+        value_name = "X,Y,Z:R",
+        allow_hyphen_values = true,
+        value_parser = parse_sphere,
+        conflicts_with = "bounds_box"
+    )]
+    bounds_sphere: Option<Shape>,
+    /// Turn boids back when they leave this box
+    #[arg(long, value_name = "X,Y,Z:X,Y,Z", allow_hyphen_values = true, value_parser = parse_box)]
+    bounds_box: Option<Shape>,
+    /// Pull the flock towards a point with strength S, or push it away if S is negative. May be
+    /// repeated
+    #[arg(
+        long = "attractor",
+        value_name = "X,Y,Z:S",
+        allow_hyphen_values = true,
+        value_parser = parse_attractor
+    )]
+    attractors: Vec<Attractor>,
+    /// Air velocity per step that carries everything along. Set flow and flow_scale with --set
+    /// for turbulence on top
+    #[arg(long, value_name = "X,Y,Z", allow_hyphen_values = true, value_parser = parse_vector)]
+    wind: Option<Vector3<f32>>,
+    /// Where tree nodes split their boids: mean, through their centroid, or median, so both
+    /// sides get as many boids. Defaults to mean
+    #[arg(long, value_name = "S", value_parser = SplitStrategy::parse)]
+    split_strategy: Option<SplitStrategy>,
+    /// Same as --split-strategy median
+    #[arg(long, conflicts_with = "split_strategy")]
+    balanced_split: bool,
+    /// Which way tree nodes split their boids: random, heading to split those ahead from those
+    /// behind, or pca to cut across the node's longest extent. Defaults to random
+    #[arg(long, value_name = "N", value_parser = SplitNormal::parse)]
+    split_normal: Option<SplitNormal>,
+    /// What boids steer by: tree, the partition tree's leaf groups, or grid, every boid within
+    /// grid_cell (see --set). Defaults to tree
+    #[arg(long, value_name = "A", value_parser = Accel::parse)]
+    accel: Option<Accel>,
+    /// Freeze motion along some of x, y and z, e.g. --lock z for a flat flock
+    #[arg(long = "lock", value_name = "AXES", value_parser = parse_axes)]
+    locked_axes: Option<[bool; 3]>,
+    /// Fail if any boid doesn't land in exactly one tree leaf
+    #[arg(long)]
+    debug_checks: bool,
+    /// Keep the flock of the last N steps, which the console's seek and back commands go back
+    /// to. Costs 44 bytes per boid per step
+    #[arg(long, value_name = "N")]
+    history_frames: Option<usize>,
+    /// Keep each boid's steering force, which the viewer draws as a magenta line
+    #[arg(long)]
+    debug_forces: bool,
+    /// Cancel the flock's overall drift so it stays in view
+    #[arg(long)]
+    hold_position: bool,
+    /// Respawn boids whose position or heading stops being finite. The viewer reports how many
+    /// it respawned
+    #[arg(long)]
+    nan_recovery: bool,
+    /// With --nan-recovery, fail the step instead once more than this fraction of the flock
+    /// breaks in it. Defaults to 0.1
+    #[arg(long, value_name = "F")]
+    max_broken_fraction: Option<f32>,
+}
+
+impl SimOptions {
+    // `base`, or the `--settings-json` file in its place, with the options that were given
+    // applied to it, checked with `Settings::validate()`
+    fn settings(&self, base: Settings) -> Result<Settings> {
+        let mut settings = match &self.settings_json {
+            Some(path) => schema::load(path)?,
+            None => base,
+        };
+        settings.n_boids = self.boids.unwrap_or(settings.n_boids);
+        settings.tree_depth = self.depth.unwrap_or(settings.tree_depth);
+        settings.num_predators = self.predators.unwrap_or(settings.num_predators);
+        settings.seed = self.seed.or(settings.seed);
+        for (name, value) in &self.weights {
+            settings.set_weight(name, *value)?;
+        }
+        settings.max_neighbor_groups = self.max_neighbors.unwrap_or(settings.max_neighbor_groups);
+        settings.neighbors = self.neighbors.unwrap_or(settings.neighbors);
+        for spec in &self.species {
+            let species = parse_species(spec, &settings)?;
+            settings.species.push(species);
+        }
+        settings
+            .obstacles
+            .extend(self.spheres.iter().chain(&self.boxes).cloned());
+        if let Some(bounds) = self.bounds_sphere.as_ref().or(self.bounds_box.as_ref()) {
+            settings.bounds = Some(bounds.clone());
+        }
+        settings.attractors.extend(self.attractors.iter().copied());
+        settings.wind = self.wind.unwrap_or(settings.wind);
+        settings.split_strategy = match self.balanced_split {
+            true => SplitStrategy::Median,
+            false => self.split_strategy.unwrap_or(settings.split_strategy),
+        };
+        settings.split_normal = self.split_normal.unwrap_or(settings.split_normal);
+        settings.accel = self.accel.unwrap_or(settings.accel);
+        settings.locked_axes = self.locked_axes.unwrap_or(settings.locked_axes);
+        settings.history_frames = self.history_frames.unwrap_or(settings.history_frames);
+        settings.debug_checks |= self.debug_checks;
+        settings.debug_forces |= self.debug_forces;
+        settings.hold_position |= self.hold_position;
+        settings.nan_recovery |= self.nan_recovery;
+        settings.max_broken_fraction = self
+            .max_broken_fraction
+            .unwrap_or(settings.max_broken_fraction);
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+// Spelled without `Option` so clap parses "off" and 0 into None, rather than treating the option
+// as one that may be left out
+type Followed = Option<Follow>;
+type Interval = Option<Duration>;
+
+/// The viewer's options. Options in a `--settings` file are read in where the file was given,
+/// so later options win over the file's
+#[derive(Debug, clap::Args)]
+struct Args {
+    /// Launch in VR. This used to be enabled by passing any argument at all
+    #[arg(long)]
+    vr: bool,
+    /// Simulate without opening a window. Events, recording, exporting, stats and --max-fps
+    /// still apply; drawing options are ignored. Use it with --steps and --export-ply to write
+    /// a fixed number of frames
+    #[arg(long)]
+    headless: bool,
+    /// Stop after N steps with --headless. Runs until killed otherwise
+    #[arg(long, value_name = "N")]
+    steps: Option<u32>,
+    /// Run another simulation with this weight changed next to the main one. Every variant
+    /// starts from the same flock when seeded. May be repeated
+    #[arg(long, value_name = "NAME=VALUE", value_parser = parse_weight)]
+    compare: Vec<(String, f32)>,
+    /// Log the state of boid INDEX every step and highlight it
+    #[arg(long, value_name = "INDEX")]
+    inspect: Option<usize>,
+    /// Print the partition tree built by the first step
+    #[arg(long)]
+    dump_tree: bool,
+    /// Run scripted events, one per line as FRAME scatter STRENGTH, FRAME set NAME VALUE, FRAME
+    /// split K or FRAME boids [+|-]N
+    #[arg(long, value_name = "FILE")]
+    events: Option<String>,
+    /// Write the boids, predators and groups of every frame to FILE
+    #[arg(long, value_name = "FILE")]
+    record: Option<String>,
+    /// Play back a recording made with --record, looping at the end. Only the first
+    /// simulation's boids and predators are shown
+    #[arg(long, value_name = "FILE")]
+    replay: Option<String>,
+    /// Write every frame's boid positions and headings to DIR as PLY point clouds,
+    /// frame_000000.ply and on. Works with --replay too
+    #[arg(long, value_name = "DIR", conflicts_with = "export_csv")]
+    export_ply: Option<String>,
+    /// The same as --export-ply, as CSV files
+    #[arg(long, value_name = "DIR")]
+    export_csv: Option<String>,
+    /// Draw the last N positions of each boid in the main simulation as a line fading out
+    /// behind it
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    trails: usize,
+    /// Read commands such as set cohere 0.8, pause or metrics from stdin while running. Type
+    /// help for the list
+    #[arg(long)]
+    console: bool,
+    /// Read options from FILE as if given here. While running, FILE is applied again as soon as
+    /// it is saved. A line reading [NAME] starts preset NAME, whose options only apply with
+    /// --preset NAME
+    #[arg(long = "settings", value_name = "FILE")]
+    settings_files: Vec<String>,
+    /// Apply preset NAME from the settings files. The console's preset command switches presets
+    /// while running
+    #[arg(long, value_name = "NAME")]
+    #[allow(dead_code)] // Read before parsing, see `preset_named()`
+    preset: Option<String>,
+    /// Print a JSON Schema of the settings files read by --settings-json and exit
+    #[arg(long)]
+    print_schema: bool,
+    /// Cap simulation and rendering at FPS frames per second
+    #[arg(long, value_name = "FPS", value_parser = parse_positive)]
+    max_fps: Option<f32>,
+    /// Print frame time, step phase times, boid, group and tree counts every SECONDS, for each
+    /// simulation. 0 turns it off
+    #[arg(
+        long = "stats",
+        value_name = "SECONDS",
+        default_value = "1",
+        value_parser = parse_interval
+    )]
+    stats_interval: Interval,
+    /// Draw the bounds as a wireframe
+    #[arg(long)]
+    show_bounds: bool,
+    /// plain, gradient to shade boids from back to front along the flock's heading, species,
+    /// group for a color per leaf group of the tree, or velocity for hue by heading and
+    /// brightness by speed. Can be changed while running with the console's color command
+    #[arg(long, value_name = "MODE", default_value = "plain", value_parser = ColorMode::parse)]
+    color_mode: ColorMode,
+    /// Gradient color at the front of the flock
+    #[arg(long, value_name = "R,G,B", default_value = "1,0.9,0.2", value_parser = parse_color)]
+    front_color: [f32; 3],
+    /// Gradient color at the back of the flock
+    #[arg(long, value_name = "R,G,B", default_value = "0.2,0.4,1", value_parser = parse_color)]
+    back_color: [f32; 3],
+    /// Move the rendered flock. The simulation itself stays at the origin
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        default_value = "0,0,0",
+        allow_hyphen_values = true,
+        value_parser = parse_vector
+    )]
+    world_pos: Vector3<f32>,
+    /// Rotate the rendered flock by Euler angles in degrees
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        default_value = "0,0,0",
+        allow_hyphen_values = true,
+        value_parser = parse_vector
+    )]
+    world_rotate: Vector3<f32>,
+    /// Scale the rendered flock
+    #[arg(long, value_name = "S", default_value = "1", value_parser = parse_positive)]
+    world_scale: f32,
+    /// Spin the rendered flock about the vertical axis by DEGREES every frame, to see it from
+    /// all sides
+    #[arg(long, value_name = "DEGREES", default_value_t = 0.)]
+    orbit: f32,
+    /// Keep the view on the main flock's centroid, on boid TARGET, or off
+    #[arg(long, value_name = "TARGET", default_value = "off", value_parser = Follow::parse)]
+    follow: Followed,
+    /// Where the view sits relative to the followed point
+    #[arg(
+        long,
+        value_name = "X,Y,Z",
+        default_value = "0,0,0",
+        allow_hyphen_values = true,
+        value_parser = parse_vector
+    )]
+    follow_offset: Vector3<f32>,
+    /// Fraction of the gap to the followed point the view leaves to close on later frames, in
+    /// [0, 1). 0 locks on
+    #[arg(long, value_name = "D", default_value = "0.9", value_parser = parse_damping)]
+    follow_damping: f32,
+    /// Boid color, components in [0, 1]
+    #[arg(long, value_name = "R,G,B", default_value = "1,1,1", value_parser = parse_color)]
+    boid_color: [f32; 3],
+    /// triangle for paper airplanes that bank into turns, or line for the old single line per
+    /// boid
+    #[arg(long, value_name = "SHAPE", default_value = "triangle", value_parser = BoidShape::parse)]
+    boid_shape: BoidShape,
+    /// Draw boids with the triangles of an OBJ model instead, facing -Z with +Y up. Vertex
+    /// colors are used if present and tinted like the built-in shapes
+    #[arg(long, value_name = "FILE")]
+    boid_mesh: Option<String>,
+    /// Size of drawn boids
+    #[arg(long, value_name = "S", default_value = "1", value_parser = parse_positive)]
+    boid_scale: f32,
+    /// Draw the main simulation's partition planes: none, all, or only those of one tree level,
+    /// 0 being the root
+    #[arg(
+        long = "planes",
+        value_name = "LEVELS",
+        default_value = "none",
+        value_parser = ShowPlanes::parse
+    )]
+    show_planes: ShowPlanes,
+    /// Partition plane color at the root, fading to white at the leaves, components in [0, 1]
+    #[arg(long, value_name = "R,G,B", default_value = "1,0.3,0", value_parser = parse_color)]
+    plane_color: [f32; 3],
+    // Last, since its help heading carries on to any options after it
+    #[command(flatten)]
+    sim: SimOptions,
+    /// Settings built from `sim` by `resolve()`
+    #[arg(skip)]
+    settings: Settings,
+    /// Every preset in the settings files
+    #[arg(skip)]
+    presets: Vec<String>,
+    /// Options as given, before `--settings` files were read in, so they can be read again
+    #[arg(skip)]
+    command_line: Vec<String>,
+}
+
+impl Args {
+    /// Parse the viewer's options from `command_line`, which mustn't name a subcommand
+    fn parse(command_line: &[String]) -> Result<Self> {
+        match Cli::parse(command_line)? {
+            Cli { tool: None, args } => Ok(args),
+            Cli { tool: Some(_), .. } => bail!("Expected the viewer's options, not a subcommand"),
+        }
+    }
+
+    // Build the settings and check what clap can't check one option at a time
+    fn resolve(&mut self, command_line: &[String], presets: Vec<String>) -> Result<()> {
+        self.settings = self.sim.settings(Settings::default())?;
+        for (name, value) in &self.compare {
+            // Catch bad names and values before launching
+            Settings::default().set_weight(name, *value)?;
+        }
+        if self.trails > trails::MAX_LEN {
+            bail!("--trails must be at most {}", trails::MAX_LEN);
+        }
+        self.command_line = command_line.to_vec();
+        self.presets = presets;
+        Ok(())
+    }
+
+    /// Last file given with `--settings`, watched for changes while running
+    fn settings_file(&self) -> Option<&String> {
+        self.settings_files.last()
+    }
+
+    /// Directory to export every frame's boids to, and the file format
+    fn export(&self) -> Option<(&str, ExportFormat)> {
+        match (&self.export_ply, &self.export_csv) {
+            (Some(dir), _) => Some((dir, ExportFormat::Ply)),
+            (_, Some(dir)) => Some((dir, ExportFormat::Csv)),
+            (None, None) => None,
+        }
+    }
+
+    fn world(&self) -> WorldTransform {
+        WorldTransform {
+            position: self.world_pos,
+            rotation: WorldTransform::rotation(self.world_rotate),
+            scale: self.world_scale,
+        }
+    }
+}
+
+// Follow each `--settings FILE` with the options in FILE, which are written as on the command
+// line but may span lines, with # starting a comment. A line reading `[NAME]` starts the section
+// for preset NAME, whose options are only kept when it is `preset`. Returns the names of all
+// presets too, in the order they were found
+fn expand_settings(args: &[String], preset: Option<&str>) -> Result<(Vec<String>, Vec<String>)> {
+    let mut expanded = vec![];
+    let mut presets: Vec<String> = vec![];
+    let mut args = args.iter().cloned();
+    while let Some(arg) = args.next() {
+        if arg != "--settings" {
+            expanded.push(arg);
+            continue;
+        }
+
+        let path = flag_value(&mut args, &arg)?;
+        let text = std::fs::read_to_string(&path).with_context(|| format!("Reading {}", path))?;
+        expanded.extend([arg, path.clone()]);
+        let mut section: Option<String> = None;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let name = name.trim().to_string();
+                if !presets.contains(&name) {
+                    presets.push(name.clone());
+                }
+                section = Some(name);
+                continue;
+            }
+            if section.is_some() && section.as_deref() != preset {
+                continue;
+            }
+            for word in line.split_whitespace() {
+                if word == "--settings" {
+                    bail!("{} can't include another settings file", path);
+                }
+                expanded.push(word.to_string());
+            }
+        }
+    }
+
+    if let Some(preset) = preset {
+        if !presets.iter().any(|p| p == preset) {
+            bail!(
+                "No preset {:?} in the settings files, found {:?}",
+                preset,
+                presets
+            );
+        }
+    }
+    Ok((expanded, presets))
+}
+
+// The last `--preset` given, which decides the sections read from settings files
+fn preset_named(args: &[String]) -> Option<String> {
+    args.windows(2)
+        .rfind(|pair| pair[0] == "--preset")
+        .map(|pair| pair[1].clone())
+}
+
+fn without_preset(args: &[String]) -> Vec<String> {
+    let mut kept = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--preset" => drop(args.next()),
+            _ => kept.push(arg.clone()),
+        }
+    }
+    kept
+}
+
+fn flag_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String> {
+    args.next()
+        .with_context(|| format!("{} requires a value", flag))
+}
+
+fn parse_weight(s: &str) -> Result<(String, f32)> {
+    let (name, value) = s
+        .split_once('=')
+        .with_context(|| format!("Expected NAME=VALUE, got {:?}", s))?;
+    Ok((name.to_string(), value.parse()?))
+}
+
+fn parse_attractor(s: &str) -> Result<Attractor> {
+    let (pos, strength) = s
+        .split_once(':')
+        .with_context(|| format!("Expected X,Y,Z:STRENGTH, got {:?}", s))?;
+    let strength: f32 = strength.parse()?;
+    if !strength.is_finite() {
+        bail!("Attractor strength must be finite, got {}", strength);
+    }
+    Ok(Attractor {
+        pos: parse_vector(pos)?,
+        strength,
+    })
+}
+
+fn parse_positive(s: &str) -> Result<f32> {
+    let value: f32 = s.parse()?;
+    if !(value > 0. && value.is_finite()) {
+        bail!("Must be positive, got {}", value);
+    }
+    Ok(value)
+}
+
+// Seconds, with 0 for never
+fn parse_interval(s: &str) -> Result<Option<Duration>> {
+    let seconds: f32 = s.parse()?;
+    if !(seconds >= 0. && seconds.is_finite()) {
+        bail!("Must be 0 or more seconds, got {}", seconds);
+    }
+    Ok(Some(Duration::from_secs_f32(seconds)).filter(|interval| *interval > Duration::ZERO))
+}
+
+fn parse_damping(s: &str) -> Result<f32> {
+    let damping: f32 = s.parse()?;
+    if !(0. ..1.).contains(&damping) {
+        bail!("Must be in [0, 1), got {}", damping);
+    }
+    Ok(damping)
+}
+
+// Comma-separated NAME=VALUE overrides of speed, cohere, steer and parallel, taking the rest from
+// `settings`
+fn parse_species(s: &str, settings: &Settings) -> Result<Species> {
+    let mut species = Species {
+        speed: settings.speed,
+        cohere: settings.cohere,
+        steer: settings.steer,
+        parallel: settings.parallel,
+    };
+    for part in s.split(',').filter(|p| !p.is_empty()) {
+        let (name, value) = parse_weight(part)?;
+        // Borrow the weight checks
+        Settings::default().set_weight(&name, value)?;
+        let weight = match name.as_str() {
+            "speed" => &mut species.speed,
+            "cohere" => &mut species.cohere,
+            "steer" => &mut species.steer,
+            "parallel" => &mut species.parallel,
+            _ => bail!(
+                "Species can set speed, cohere, steer and parallel, not {:?}",
+                name
+            ),
+        };
+        *weight = value;
+    }
+    Ok(species)
+}
+
+fn parse_sphere(s: &str) -> Result<Shape> {
+    let (center, radius) = s
+        .split_once(':')
+        .with_context(|| format!("Expected X,Y,Z:RADIUS, got {:?}", s))?;
+    let radius: f32 = radius.parse()?;
+    if !(radius >= 0. && radius.is_finite()) {
+        bail!("Sphere radius must be non-negative, got {}", radius);
+    }
+    Ok(Shape::Sphere {
+        center: parse_vector(center)?,
+        radius,
+    })
+}
+
+fn parse_box(s: &str) -> Result<Shape> {
+    let (a, b) = s
+        .split_once(':')
+        .with_context(|| format!("Expected X,Y,Z:X,Y,Z, got {:?}", s))?;
+    let (a, b) = (parse_vector(a)?, parse_vector(b)?);
+    Ok(Shape::Box {
+        min: a.inf(&b),
+        max: a.sup(&b),
+    })
+}
+
+fn parse_color(s: &str) -> Result<[f32; 3]> {
+    let color = parse_triple(s)?;
+    for c in &color {
+        if !(0. ..=1.).contains(c) {
+            bail!("Color component {} in {:?} is outside [0, 1]", c, s);
+        }
+    }
+    Ok(color)
+}
+
+fn parse_vector(s: &str) -> Result<Vector3<f32>> {
+    let v = parse_triple(s)?;
+    if v.iter().any(|c| !c.is_finite()) {
+        bail!("Vector {:?} has non-finite components", s);
+    }
+    Ok(v.into())
+}
+
+fn parse_triple(s: &str) -> Result<[f32; 3]> {
+    let mut out = [0.; 3];
+    let mut parts = s.split(',');
+    for c in &mut out {
+        *c = parts
+            .next()
+            .with_context(|| format!("{:?} needs three components", s))?
+            .trim()
+            .parse()?;
+    }
+    if parts.next().is_some() {
+        bail!("{:?} has more than three components", s);
+    }
+    Ok(out)
+}
+
+fn parse_axes(s: &str) -> Result<[bool; 3]> {
+    let mut axes = [false; 3];
+    for c in s.chars() {
+        match c {
+            'x' => axes[0] = true,
+            'y' => axes[1] = true,
+            'z' => axes[2] = true,
+            _ => bail!("Unknown axis {:?} in {:?}, expected x, y or z", c, s),
+        }
+    }
+    Ok(axes)
+}
+
+fn main() -> Result<()> {
+    let command_line: Vec<String> = std::env::args().skip(1).collect();
+    let cli = match Cli::parse(&command_line) {
+        Ok(cli) => cli,
+        // Let clap print help, or its error with the usage, and exit the way it does
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(e) => e.exit(),
+            Err(e) => return Err(e),
+        },
+    };
+
+    match cli.tool {
+        Some(Tool::Sweep(options)) => sweep::run(options),
+        Some(Tool::Selftest) => selftest::run(),
+        Some(Tool::Compare(options)) => compare::run(options),
+        Some(Tool::Bench(options)) => bench::run(options),
+        None if cli.args.print_schema => {
+            println!("{}", schema::schema());
+            Ok(())
+        }
+        None if cli.args.headless => headless::run(cli.args),
+        None => launch::<MyApp>(cli.args.vr, cli.args),
+    }
+}
+
+// The main simulation followed by one for each --compare variant
+fn simulations(args: &Args) -> Result<Vec<Simulation>> {
+    let mut sims = vec![Simulation::new(args.settings.clone())];
+    for (name, value) in &args.compare {
+        let mut settings = args.settings.clone();
+        settings.set_weight(name, *value)?;
+        sims.push(Simulation::new(settings));
+    }
+    Ok(sims)
+}
+
+// One simulation's stats as a line of text. Simulations after the first are numbered
+fn stats_line(idx: usize, frame_time: Duration, stats: &StepStats) -> String {
+    let ms = |d: Duration| d.as_secs_f64() * 1e3;
+    let t = &stats.timings;
+    format!(
+        "{}frame {}: {:.1} ms per frame, step {:.2} ms (select {:.2}, reduce {:.2}, motion {:.2}, \
+         predators {:.2}), {} boids, {} predators, {} groups, depth {}, {} respawned",
+        match idx {
+            0 => String::new(),
+            n => format!("[{}] ", n),
+        },
+        stats.frame,
+        ms(frame_time),
+        ms(t.total()),
+        ms(t.select),
+        ms(t.reduce),
+        ms(t.motion),
+        ms(t.predators),
+        stats.boids,
+        stats.predators,
+        stats.groups,
+        stats.tree_depth,
+        stats.respawned
+    )
+}
+
+fn boid(color: [f32; 3], length: f32) -> (Vec<Vertex>, Vec<u16>) {
+    let vertices = vec![
+        Vertex::new([0.0, 0.0, 0.0], color),
+        Vertex::new([0.0, length, 0.0], color),
+    ];
+
+    let indices = vec![0, 1];
+
+    (vertices, indices)
+}
+
+fn obstacle_meshes(
+    engine: &mut dyn Engine,
+    settings: &Settings,
+    show_bounds: bool,
+) -> Result<Vec<Mesh>> {
+    let mut meshes = vec![];
+    for obstacle in &settings.obstacles {
+        let (vertices, indices) = wireframe(obstacle, [0.5, 0.5, 0.5]);
+        meshes.push(engine.add_mesh(&vertices, &indices)?);
+    }
+    if let (Some(bounds), true) = (&settings.bounds, show_bounds) {
+        let (vertices, indices) = wireframe(bounds, [0.2, 0.5, 0.5]);
+        meshes.push(engine.add_mesh(&vertices, &indices)?);
+    }
+    Ok(meshes)
+}
+
+fn wireframe(shape: &Shape, color: [f32; 3]) -> (Vec<Vertex>, Vec<u16>) {
+    let mut vertices = vec![];
+    let mut indices = vec![];
+    match shape {
+        // A circle around each axis
+        Shape::Sphere { center, radius } => {
+            const SEGMENTS: u16 = 32;
+            for axis in 0..3 {
+                let base = vertices.len() as u16;
+                for i in 0..SEGMENTS {
+                    let angle = i as f32 / SEGMENTS as f32 * std::f32::consts::TAU;
+                    let mut p = [0.; 3];
+                    p[(axis + 1) % 3] = angle.cos() * radius;
+                    p[(axis + 2) % 3] = angle.sin() * radius;
+                    let p = center + Vector3::from(p);
+                    vertices.push(Vertex::new([p.x, p.y, p.z], color));
+                    indices.push(base + i);
+                    indices.push(base + (i + 1) % SEGMENTS);
+                }
+            }
+        }
+        Shape::Box { min, max } => {
+            // Corner i takes max on the axes whose bit is set in i
+            for i in 0..8 {
+                let pick = |axis: usize| match i & (1 << axis) {
+                    0 => min[axis],
+                    _ => max[axis],
+                };
+                vertices.push(Vertex::new([pick(0), pick(1), pick(2)], color));
+            }
+            for i in 0..8u16 {
+                for axis in 0..3 {
+                    if i & (1 << axis) == 0 {
+                        indices.push(i);
+                        indices.push(i | (1 << axis));
+                    }
+                }
+            }
+        }
+    }
+    (vertices, indices)
+}
+
+fn cross(size: f32, color: [f32; 3]) -> (Vec<Vertex>, Vec<u16>) {
+    let vertices = vec![
+        Vertex::new([-size, 0., 0.], color),
+        Vertex::new([size, 0., 0.], color),
+        Vertex::new([0., -size, 0.], color),
+        Vertex::new([0., size, 0.], color),
+        Vertex::new([0., 0., -size], color),
+        Vertex::new([0., 0., size], color),
+    ];
+
+    let indices = vec![0, 1, 2, 3, 4, 5];
+
+    (vertices, indices)
+}
+
+// A square outline facing +Y, which `point_towards()` turns to face a plane's normal
+fn plane(size: f32, color: [f32; 3]) -> (Vec<Vertex>, Vec<u16>) {
+    let vertices = vec![
+        Vertex::new([size, 0., size], color),
+        Vertex::new([size, 0., -size], color),
+        Vertex::new([-size, 0., -size], color),
+        Vertex::new([-size, 0., size], color),
+    ];
+
+    let indices = vec![0, 1, 1, 2, 2, 3, 3, 0];
+
+    (vertices, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_string).collect()
+    }
+
+    // A settings file per test, since tests run side by side in one process
+    fn settings_file(name: &str, text: &str) -> String {
+        let file = format!("fast_boids_cli_{}_{}", std::process::id(), name);
+        let path = std::env::temp_dir().join(file);
+        std::fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    fn tool(command_line: &str) -> Option<Tool> {
+        let program = std::iter::once("fast_boids".to_string());
+        Cli::try_parse_from(program.chain(words(command_line)))
+            .unwrap()
+            .tool
+    }
+
+    #[test]
+    fn every_subcommand_parses() {
+        assert!(matches!(tool("sweep cohere=0:1:3"), Some(Tool::Sweep(_))));
+        assert!(matches!(tool("selftest"), Some(Tool::Selftest)));
+        assert!(matches!(tool("compare --depth 3"), Some(Tool::Compare(_))));
+        assert!(matches!(tool("bench --steps 10"), Some(Tool::Bench(_))));
+        assert!(matches!(tool("--bench --boids 10"), Some(Tool::Bench(_))));
+        assert!(tool("--boids 10").is_none());
+    }
+
+    #[test]
+    fn bad_command_lines_are_rejected() {
+        for command_line in [
+            "export out",
+            "sweep",
+            "sweep cohere=0,1",
+            "--vr sweep cohere=0:1:3",
+            "--export-ply a --export-csv b",
+            "--frobnicate",
+            "--boids 0",
+            "--depth 40",
+            "--follow-damping 1",
+            "--max-broken-fraction 2",
+        ] {
+            let parsed = Cli::parse(&words(command_line));
+            assert!(parsed.is_err(), "{:?} parsed", command_line);
+        }
+    }
+
+    #[test]
+    fn viewer_options_reach_the_settings() {
+        let args = Args::parse(&words(
+            "--boids 100 --set cohere=0.5 --species speed=0.06 --sphere 1,-2,3:4 --lock z \
+             --nan-recovery --max-broken-fraction 0.5 --history-frames 30",
+        ))
+        .unwrap();
+        let settings = &args.settings;
+        assert_eq!(settings.n_boids, 100);
+        assert_eq!(settings.cohere, 0.5);
+        assert_eq!(settings.species.len(), 1);
+        assert_eq!(settings.species[0].speed, 0.06);
+        assert_eq!(settings.species[0].cohere, 0.5);
+        assert_eq!(settings.obstacles.len(), 1);
+        assert_eq!(settings.locked_axes, [false, false, true]);
+        assert!(settings.nan_recovery);
+        assert_eq!(settings.max_broken_fraction, 0.5);
+        assert_eq!(settings.history_frames, 30);
+    }
+
+    #[test]
+    fn settings_files_read_their_preset() {
+        let path = settings_file(
+            "preset",
+            "--boids 50 # shared\n[fast]\n--set speed=0.1\n[slow]\n--depth 2\n",
+        );
+        let (expanded, presets) =
+            expand_settings(&words(&format!("--settings {}", path)), Some("fast")).unwrap();
+        assert_eq!(presets, ["fast", "slow"]);
+        let expected = words(&format!("--settings {} --boids 50 --set speed=0.1", path));
+        assert_eq!(expanded, expected);
+        assert!(expand_settings(&words(&format!("--settings {}", path)), Some("medium")).is_err());
+
+        // Options after the file win over it
+        let command_line = format!("--settings {} --preset slow --depth 3", path);
+        let args = Args::parse(&words(&command_line)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(args.settings.n_boids, 50);
+        assert_eq!(args.settings.tree_depth, 3);
+        assert_eq!(args.presets, ["fast", "slow"]);
+    }
+
+    #[test]
+    fn json_settings_are_applied_under_the_options() {
+        let path = settings_file("json", r#"{"n_boids": 50, "tree_depth": 2, "cohere": 2}"#);
+        let command_line = format!("--settings-json {} --depth 3", path);
+        let args = Args::parse(&words(&command_line)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(args.settings.n_boids, 50);
+        assert_eq!(args.settings.tree_depth, 3);
+        assert_eq!(args.settings.cohere, 2.);
+    }
+
+    #[test]
+    fn unknown_options_in_settings_files_are_rejected() {
+        let path = settings_file("unknown", "--boids 50\n--frobnicate 3\n");
+        let parsed = Cli::parse(&words(&format!("--settings {}", path)));
+        std::fs::remove_file(&path).unwrap();
+        assert!(parsed.is_err());
+    }
+}
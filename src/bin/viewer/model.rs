@@ -0,0 +1,176 @@
+use anyhow::{bail, Context, Result};
+use klystron::{DrawType, Engine, Mesh, Vertex};
+use nalgebra::Vector3;
+
+/// What a boid is drawn as
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BoidShape {
+    /// A line along the heading
+    Line,
+    /// A paper airplane: wings that bank with the boid's roll and a keel that shows which way is
+    /// up
+    Triangle,
+}
+
+impl BoidShape {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "line" => Self::Line,
+            "triangle" => Self::Triangle,
+            _ => bail!("Unknown boid shape {:?}, expected line or triangle", s),
+        })
+    }
+}
+
+/// Geometry every boid mesh is built from, one unit long. Positions are in the frame
+/// `point_towards()` turns to face a boid's heading: forward along +Y and up along +Z. Vertex
+/// colors are multiplied by the color the boid is drawn in
+pub struct BoidModel {
+    positions: Vec<[f32; 3]>,
+    colors: Vec<[f32; 3]>,
+    indices: Vec<u16>,
+    draw_type: DrawType,
+}
+
+impl BoidModel {
+    pub fn from_shape(shape: BoidShape) -> Self {
+        match shape {
+            BoidShape::Line => Self {
+                positions: vec![[0., 0., 0.], [0., 1., 0.]],
+                colors: vec![[1.; 3]; 2],
+                indices: vec![0, 1],
+                draw_type: DrawType::Lines,
+            },
+            // Wings along X, keel hanging down -Z and darker. Each face is wound both ways so it
+            // shows from either side
+            BoidShape::Triangle => Self {
+                positions: vec![
+                    [0., 1., 0.],
+                    [-0.35, 0., 0.],
+                    [0.35, 0., 0.],
+                    [0., 1., 0.],
+                    [0., 0., 0.],
+                    [0., 0., -0.2],
+                ],
+                colors: vec![[1.; 3], [1.; 3], [1.; 3], [0.6; 3], [0.6; 3], [0.6; 3]],
+                indices: vec![0, 1, 2, 0, 2, 1, 3, 4, 5, 3, 5, 4],
+                draw_type: DrawType::Triangles,
+            },
+        }
+    }
+
+    /// Read a Wavefront OBJ model, see `parse_obj()`
+    pub fn load_obj(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+        Self::parse_obj(&text).with_context(|| format!("Loading {}", path))
+    }
+
+    /// Triangles from the `v` and `f` lines of an OBJ file; everything else is ignored. The model
+    /// should face -Z with +Y up, as Blender exports it by default, and is used at the size it was
+    /// modeled at. Vertices may carry an `r g b` color after their position. Faces without one
+    /// are shaded gray by how far they face up, so the shape still reads
+    pub fn parse_obj(text: &str) -> Result<Self> {
+        let mut points: Vec<(Vector3<f32>, Option<[f32; 3]>)> = Vec::new();
+        let mut faces: Vec<Vec<usize>> = Vec::new();
+
+        for (line_idx, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match words.split_first() {
+                Some((&"v", values)) => parse_point(values).map(|point| points.push(point)),
+                Some((&"f", corners)) => parse_face(corners, points.len()).map(|f| faces.push(f)),
+                _ => Ok(()),
+            };
+            parsed.with_context(|| format!("Line {}", line_idx + 1))?;
+        }
+        if faces.is_empty() {
+            bail!("No faces");
+        }
+
+        // Every corner gets its own vertex, so faces without vertex colors can be shaded flat
+        let light = Vector3::new(0., 0.5, 1.).normalize();
+        let mut model = Self {
+            positions: Vec::new(),
+            colors: Vec::new(),
+            indices: Vec::new(),
+            draw_type: DrawType::Triangles,
+        };
+        for face in &faces {
+            for i in 1..face.len() - 1 {
+                let corners = [face[0], face[i], face[i + 1]];
+                let [a, b, c] = corners.map(|idx| points[idx].0);
+                let normal = (b - a).cross(&(c - a)).try_normalize(1e-12);
+                let shade = 0.5 + 0.5 * normal.map_or(0., |n| n.dot(&light).abs());
+
+                for &idx in &corners {
+                    let (pos, color) = points[idx];
+                    if model.positions.len() == u16::MAX as usize + 1 {
+                        bail!(
+                            "More than {} triangles, which is all one mesh can hold",
+                            (u16::MAX as usize + 1) / 3
+                        );
+                    }
+                    model.indices.push(model.positions.len() as u16);
+                    model.positions.push([pos.x, pos.y, pos.z]);
+                    model.colors.push(color.unwrap_or([shade; 3]));
+                }
+            }
+        }
+        Ok(model)
+    }
+
+    pub fn draw_type(&self) -> DrawType {
+        self.draw_type
+    }
+
+    /// Upload the model scaled by `length` and tinted `color`
+    pub fn mesh(&self, engine: &mut dyn Engine, color: [f32; 3], length: f32) -> Result<Mesh> {
+        let vertices: Vec<Vertex> = self
+            .positions
+            .iter()
+            .zip(&self.colors)
+            .map(|(pos, tint)| {
+                Vertex::new(
+                    pos.map(|x| x * length),
+                    [color[0] * tint[0], color[1] * tint[1], color[2] * tint[2]],
+                )
+            })
+            .collect();
+        engine.add_mesh(&vertices, &self.indices)
+    }
+}
+
+// `x y z` or `x y z r g b`, turned from OBJ's -Z forward, +Y up into the boid frame
+fn parse_point(values: &[&str]) -> Result<(Vector3<f32>, Option<[f32; 3]>)> {
+    let values = values
+        .iter()
+        .map(|v| v.parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()?;
+    let color = match values.len() {
+        3 | 4 => None,
+        6 => Some([values[3], values[4], values[5]]),
+        n => bail!("Expected 3 or 6 numbers on a vertex, found {}", n),
+    };
+    Ok((Vector3::new(values[0], -values[2], values[1]), color))
+}
+
+// Corners are `v`, `v/vt`, `v//vn` or `v/vt/vn`, counting vertices from 1, or back from the last
+// one read when negative
+fn parse_face(corners: &[&str], n_points: usize) -> Result<Vec<usize>> {
+    if corners.len() < 3 {
+        bail!("A face needs at least 3 corners, found {}", corners.len());
+    }
+    corners
+        .iter()
+        .map(|corner| {
+            let index: i64 = corner.split('/').next().unwrap_or("").parse()?;
+            let resolved = match index {
+                i if i > 0 => i - 1,
+                i => n_points as i64 + i,
+            };
+            if index == 0 || resolved < 0 || resolved >= n_points as i64 {
+                bail!("Vertex {} isn't defined", index);
+            }
+            Ok(resolved as usize)
+        })
+        .collect()
+}
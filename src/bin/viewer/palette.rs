@@ -0,0 +1,157 @@
+use crate::model::BoidModel;
+use anyhow::{bail, Result};
+use fast_boids::sim::Simulation;
+use klystron::{Engine, Mesh};
+use nalgebra::Vector3;
+use std::collections::HashMap;
+
+/// Steps per color channel. Colors are baked into mesh vertices, so every distinct color is its
+/// own mesh; quantizing keeps the number of meshes bounded
+const LEVELS: f32 = 15.;
+
+/// How boids are colored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Everything in the boid color
+    Plain,
+    /// Blend from the back color to the front color along the flock's direction of travel
+    Gradient,
+    /// A different color for each species
+    Species,
+    /// A different color for each leaf group, to see how the tree cut up the flock
+    Group,
+    /// Hue from the direction a boid is heading around the vertical axis, brightness from its
+    /// speed relative to the fastest boid
+    Velocity,
+}
+
+impl ColorMode {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "plain" => Self::Plain,
+            "gradient" => Self::Gradient,
+            "species" => Self::Species,
+            "group" => Self::Group,
+            "velocity" => Self::Velocity,
+            _ => bail!(
+                "Unknown color mode {:?}, expected plain, gradient, species, group or velocity",
+                s
+            ),
+        })
+    }
+}
+
+/// Boid meshes by color, created on first use
+pub struct Palette {
+    model: BoidModel,
+    meshes: HashMap<[u8; 3], Mesh>,
+}
+
+impl Palette {
+    pub fn new(model: BoidModel) -> Self {
+        Self {
+            model,
+            meshes: HashMap::new(),
+        }
+    }
+
+    pub fn mesh(&mut self, engine: &mut dyn Engine, color: [f32; 3]) -> Result<Mesh> {
+        let key = color.map(|c| (c.clamp(0., 1.) * LEVELS).round() as u8);
+        if let Some(mesh) = self.meshes.get(&key) {
+            return Ok(*mesh);
+        }
+
+        let mesh = self
+            .model
+            .mesh(engine, key.map(|c| c as f32 / LEVELS), 1.)?;
+        self.meshes.insert(key, mesh);
+        Ok(mesh)
+    }
+}
+
+/// Position of each boid along the flock's heading, scaled to `[0, 1]` from the rearmost to the
+/// frontmost boid
+pub struct Gradient {
+    origin: Vector3<f32>,
+    dir: Vector3<f32>,
+    min: f32,
+    range: f32,
+}
+
+impl Gradient {
+    /// Uses the root group's centroid and heading from the last tree build
+    pub fn new(sim: &Simulation) -> Option<Self> {
+        let root = sim.groups().into_iter().next()?;
+        let dir = root.heading.try_normalize(1e-6)?;
+        let project = |pos: &Vector3<f32>| (pos - root.pos).dot(&dir);
+
+        let (min, max) = sim
+            .boids()
+            .iter()
+            .map(|b| project(&b.pos))
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), t| {
+                (min.min(t), max.max(t))
+            });
+
+        Some(Self {
+            origin: root.pos,
+            dir,
+            min,
+            range: (max - min).max(1e-6),
+        })
+    }
+
+    pub fn t(&self, pos: &Vector3<f32>) -> f32 {
+        ((pos - self.origin).dot(&self.dir) - self.min) / self.range
+    }
+}
+
+/// Colors for the first few species, repeating after that
+const SPECIES_COLORS: [[f32; 3]; 6] = [
+    [1., 1., 1.],
+    [1., 0.6, 0.1],
+    [0.2, 0.8, 1.],
+    [0.6, 1., 0.3],
+    [1., 0.4, 0.8],
+    [0.7, 0.5, 1.],
+];
+
+pub fn species_color(species: u32) -> [f32; 3] {
+    SPECIES_COLORS[species as usize % SPECIES_COLORS.len()]
+}
+
+/// Hues for consecutive group ids step by the golden ratio, so sibling leaves, whose ids are
+/// adjacent, get clearly different colors. Species are offset from each other by half a turn
+pub fn group_color(species: u32, id: u32) -> [f32; 3] {
+    let hue = (id as f32 * 0.618_034 + species as f32 * 0.5).fract();
+    hsv(hue, 0.8, 1.)
+}
+
+/// Color for a boid heading along `heading` at `speed`, out of `fastest` in the flock
+pub fn velocity_color(heading: Vector3<f32>, speed: f32, fastest: f32) -> [f32; 3] {
+    let hue = heading.z.atan2(heading.x) / std::f32::consts::TAU;
+    let brightness = match fastest > 0. {
+        true => speed / fastest,
+        false => 1.,
+    };
+    // Kept off black so that slow boids still show up
+    hsv(hue, 0.8, 0.25 + 0.75 * brightness)
+}
+
+/// Color from hue, saturation and value, all in `[0, 1]`
+pub fn hsv(hue: f32, saturation: f32, value: f32) -> [f32; 3] {
+    let h = hue.rem_euclid(1.) * 6.;
+    let f = |n: f32| {
+        let k = (n + h) % 6.;
+        value - value * saturation * k.min(4. - k).clamp(0., 1.)
+    };
+    [f(5.), f(3.), f(1.)]
+}
+
+pub fn lerp_color(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    let mut out = [0.; 3];
+    for ((o, a), b) in out.iter_mut().zip(&a).zip(&b) {
+        *o = a + (b - a) * t;
+    }
+    out
+}
@@ -0,0 +1,80 @@
+use anyhow::{bail, Result};
+use fast_boids::sim::{self, Boid, Settings, Simulation};
+
+const STEPS: usize = 100;
+const N_BOIDS: usize = 1000;
+
+/// Run a small simulation and check the invariants it should always keep, as a quick diagnostic
+/// to paste into bug reports. Prints PASS or FAIL for each check and fails if any did. The
+/// library's behaviour beyond these is covered by the tests in `tests/`
+pub fn run() -> Result<()> {
+    let settings = Settings {
+        n_boids: N_BOIDS,
+        tree_depth: 4,
+        debug_checks: true,
+        // Seeded so that a failure can be reproduced
+        seed: Some(0),
+        ..Settings::default()
+    };
+    let mut sim = Simulation::new(settings.clone());
+
+    let mut failures = 0;
+    let mut check = |name: &str, result: Result<()>| match result {
+        Ok(()) => println!("PASS {}", name),
+        Err(e) => {
+            println!("FAIL {}: {:#}", name, e);
+            failures += 1;
+        }
+    };
+
+    let before = sim.boids().to_vec();
+    let groups = sim.step()?;
+    check(
+        "reduction matches CPU sum",
+        sim::check_root(&before, &groups, 1e-3),
+    );
+
+    let mut exact = Simulation::new(Settings {
+        deterministic_reduce: true,
+        ..settings.clone()
+    });
+    let before = exact.boids().to_vec();
+    let groups = exact.step()?;
+    check(
+        "ordered reduction matches CPU sum exactly",
+        sim::check_root(&before, &groups, 0.),
+    );
+
+    // debug_checks makes step() fail as soon as a boid misses the leaves
+    let leaves = (1..STEPS).try_for_each(|_| sim.step().map(drop));
+    check("every boid lands in one leaf", leaves);
+
+    let groups = sim.groups();
+    check(
+        "leaf counts add up to each tree",
+        sim::check_ids(sim.boids(), &groups),
+    );
+
+    check("boid count conserved", check_count(sim.boids()));
+    check("boids stay finite", check_finite(sim.boids()));
+
+    if failures > 0 {
+        bail!("{} self-test checks failed", failures);
+    }
+    Ok(())
+}
+
+fn check_count(boids: &[Boid]) -> Result<()> {
+    if boids.len() != N_BOIDS {
+        bail!("{} boids after {} steps", boids.len(), STEPS);
+    }
+    Ok(())
+}
+
+fn check_finite(boids: &[Boid]) -> Result<()> {
+    let broken = boids.iter().filter(|b| !b.is_finite()).count();
+    if broken > 0 {
+        bail!("{} boids are non-finite", broken);
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use crate::SimOptions;
+use anyhow::{bail, Context, Result};
+use fast_boids::sim::{Settings, Simulation};
+use std::fs::File;
+use std::io::Write;
+
+/// Options of `fast_boids sweep`
+#[derive(Debug, clap::Args)]
+pub struct Options {
+    /// Weights to sweep, each over COUNT evenly spaced values from START to END inclusive
+    #[arg(value_name = "NAME=START:END:COUNT", required = true, value_parser = parse_axis)]
+    axes: Vec<Axis>,
+    /// Steps per run
+    #[arg(long, value_name = "N", default_value_t = 500)]
+    steps: usize,
+    /// CSV to write
+    #[arg(long, value_name = "FILE", default_value = "sweep.csv")]
+    out: String,
+    #[command(flatten)]
+    sim: SimOptions,
+}
+
+#[derive(Debug, Clone)]
+struct Axis {
+    name: String,
+    values: Vec<f32>,
+}
+
+pub fn run(options: Options) -> Result<()> {
+    let Options {
+        axes,
+        steps,
+        out,
+        sim,
+    } = options;
+    let base = sim.settings(Settings {
+        n_boids: 4096,
+        seed: Some(0),
+        ..Settings::default()
+    })?;
+
+    let mut csv = File::create(&out).with_context(|| format!("Creating {}", out))?;
+    let names: Vec<&str> = axes.iter().map(|a| a.name.as_str()).collect();
+    writeln!(csv, "{},polarization", names.join(","))?;
+
+    // Count through every combination like an odometer, last axis fastest
+    let total: usize = axes.iter().map(|a| a.values.len()).product();
+    let mut idx = vec![0; axes.len()];
+    for run in 0..total {
+        let mut settings = base.clone();
+        for (axis, &i) in axes.iter().zip(&idx) {
+            settings.set_weight(&axis.name, axis.values[i])?;
+        }
+        settings.validate()?;
+
+        let mut sim = Simulation::new(settings);
+        for _ in 0..steps {
+            sim.step()?;
+        }
+
+        let values: Vec<String> = axes
+            .iter()
+            .zip(&idx)
+            .map(|(axis, &i)| axis.values[i].to_string())
+            .collect();
+        writeln!(csv, "{},{}", values.join(","), sim.polarization())?;
+        println!("Run {}/{}: {}", run + 1, total, values.join(", "));
+
+        for (axis, i) in axes.iter().zip(idx.iter_mut()).rev() {
+            *i += 1;
+            if *i < axis.values.len() {
+                break;
+            }
+            *i = 0;
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_axis(spec: &str) -> Result<Axis> {
+    let (name, range) = spec
+        .split_once('=')
+        .with_context(|| format!("Expected NAME=START:END:COUNT, got {:?}", spec))?;
+
+    let parts: Vec<&str> = range.split(':').collect();
+    let (start, end, count): (f32, f32, usize) = match parts.as_slice() {
+        [start, end, count] => (start.parse()?, end.parse()?, count.parse()?),
+        _ => bail!("Expected START:END:COUNT for {}, got {:?}", name, range),
+    };
+    if count == 0 {
+        bail!("Sweep of {} needs at least one value", name);
+    }
+
+    // Check the name up front rather than partway through the sweep
+    Settings::default().weight(name)?;
+
+    let values = (0..count)
+        .map(|i| match count {
+            1 => start,
+            _ => start + (end - start) * i as f32 / (count - 1) as f32,
+        })
+        .collect();
+
+    Ok(Axis {
+        name: name.to_string(),
+        values,
+    })
+}
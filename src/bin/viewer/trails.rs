@@ -0,0 +1,93 @@
+use anyhow::Result;
+use fast_boids::sim::Boid;
+use klystron::{Engine, Mesh, Vertex};
+use nalgebra::Vector3;
+use std::collections::VecDeque;
+
+/// A step longer than this breaks the trail instead of being drawn, so that boids that respawn or
+/// a replay that loops don't leave lines across the whole flock
+const MAX_SEGMENT: f32 = 1.;
+
+/// Vertices in one mesh, as meshes index them with u16
+const MAX_VERTICES: usize = 1 << 16;
+
+/// Longest trail, in frames, that still fits one boid's trail in a mesh
+pub const MAX_LEN: usize = MAX_VERTICES;
+
+/// The last positions of every boid, drawn as lines that fade out towards the oldest end
+pub struct Trails {
+    len: usize,
+    color: [f32; 3],
+    /// Positions of the whole flock on each of the last `len` frames, oldest first
+    frames: VecDeque<Vec<Vector3<f32>>>,
+    /// Meshes from the last `meshes()`, removed on the next
+    meshes: Vec<Mesh>,
+}
+
+impl Trails {
+    pub fn new(len: usize, color: [f32; 3]) -> Self {
+        Self {
+            len,
+            color,
+            frames: VecDeque::with_capacity(len),
+            meshes: Vec::new(),
+        }
+    }
+
+    /// Add the flock's current positions. A change in flock size starts the trails over
+    pub fn push(&mut self, boids: &[Boid]) {
+        if self.frames.back().map(Vec::len) != Some(boids.len()) {
+            self.frames.clear();
+        }
+        if self.frames.len() == self.len {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(boids.iter().map(|b| b.pos).collect());
+    }
+
+    /// Rebuild the trail meshes from the stored positions. Trails change every frame, so the last
+    /// frame's meshes are dropped rather than kept around
+    pub fn meshes(&mut self, engine: &mut dyn Engine) -> Result<&[Mesh]> {
+        for mesh in self.meshes.drain(..) {
+            engine.remove_mesh(mesh)?;
+        }
+
+        let n_boids = self.frames.back().map_or(0, Vec::len);
+        let n_frames = self.frames.len();
+        if n_frames < 2 || n_boids == 0 {
+            return Ok(&self.meshes);
+        }
+
+        // Colors only depend on age, from black at the oldest frame to full color at the newest
+        let colors: Vec<[f32; 3]> = (0..n_frames)
+            .map(|age| {
+                let t = (age + 1) as f32 / n_frames as f32;
+                [self.color[0] * t, self.color[1] * t, self.color[2] * t]
+            })
+            .collect();
+
+        let per_mesh = (MAX_VERTICES / n_frames).max(1);
+        for start in (0..n_boids).step_by(per_mesh) {
+            let mut vertices = Vec::new();
+            let mut indices = Vec::new();
+            for idx in start..n_boids.min(start + per_mesh) {
+                let base = vertices.len() as u16;
+                for (frame, color) in self.frames.iter().zip(&colors) {
+                    let pos = frame[idx];
+                    vertices.push(Vertex::new([pos.x, pos.y, pos.z], *color));
+                }
+                for age in 1..n_frames {
+                    let (a, b) = (&self.frames[age - 1][idx], &self.frames[age][idx]);
+                    if (b - a).magnitude() <= MAX_SEGMENT {
+                        indices.push(base + age as u16 - 1);
+                        indices.push(base + age as u16);
+                    }
+                }
+            }
+            if !indices.is_empty() {
+                self.meshes.push(engine.add_mesh(&vertices, &indices)?);
+            }
+        }
+        Ok(&self.meshes)
+    }
+}
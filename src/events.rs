@@ -0,0 +1,110 @@
+use crate::sim::Simulation;
+use anyhow::{bail, Context, Result};
+
+/// Something done to the running simulation at a given frame
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// See `Simulation::scatter`
+    Scatter(f32),
+    /// Change a weight, as `--set` does at startup
+    SetWeight(String, f32),
+    /// See `Simulation::split`
+    Split(usize),
+    /// Grow or shrink the flock to this many boids, see `Simulation::set_boid_count`
+    Boids(usize),
+    /// Grow or shrink the flock by this many boids, keeping at least one
+    AddBoids(i64),
+}
+
+impl Event {
+    /// Parse `scatter STRENGTH`, `set NAME VALUE`, `split K` or `boids N`, already split into
+    /// words. `boids +N` and `boids -N` change the flock size by N instead
+    pub fn parse(words: &[&str]) -> Result<Self> {
+        let event = match words {
+            ["scatter", strength] => Event::Scatter(strength.parse()?),
+            ["set", name, value] => Event::SetWeight(name.to_string(), value.parse()?),
+            ["split", k] => Event::Split(k.parse()?),
+            ["boids", n] if n.starts_with(&['+', '-'][..]) => Event::AddBoids(n.parse()?),
+            ["boids", n] => match n.parse()? {
+                0 => bail!("A flock needs at least one boid"),
+                n => Event::Boids(n),
+            },
+            _ => bail!(
+                "Expected scatter STRENGTH, set NAME VALUE, split K or boids N, got {:?}",
+                words.join(" ")
+            ),
+        };
+
+        // Catch bad names and values before the event comes up
+        if let Event::SetWeight(name, value) = &event {
+            crate::sim::Settings::default().set_weight(name, *value)?;
+        }
+
+        Ok(event)
+    }
+
+    pub fn apply(&self, sim: &mut Simulation) -> Result<()> {
+        match self {
+            Event::Scatter(strength) => sim.scatter(*strength),
+            Event::SetWeight(name, value) => sim.set_weight(name, *value)?,
+            Event::Split(k) => sim.split(*k)?,
+            Event::Boids(n) => sim.set_boid_count(*n)?,
+            Event::AddBoids(n) => {
+                let n = (sim.boids().len() as i64 + n).max(1);
+                sim.set_boid_count(n as usize)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Events in frame order, read from a file with one event per line:
+///
+/// ```text
+/// # frame event args...
+/// 120 scatter 0.5
+/// 300 set cohere 2
+/// 600 split 4
+/// 900 boids +4096
+/// ```
+#[derive(Debug, Default)]
+pub struct Timeline {
+    events: Vec<(u32, Event)>,
+    /// Index of the first event that hasn't fired yet
+    next: usize,
+}
+
+impl Timeline {
+    pub fn load(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+        let mut events = vec![];
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let event = parse_line(line).with_context(|| format!("{}:{}", path, line_no + 1))?;
+            events.push(event);
+        }
+
+        // Stable, so events on the same frame keep the order they were written in
+        events.sort_by_key(|(frame, _)| *frame);
+        Ok(Self { events, next: 0 })
+    }
+
+    /// Events due at `frame` that haven't fired yet, marking them as fired. Events scheduled for
+    /// a frame that was skipped over fire late rather than never
+    pub fn due(&mut self, frame: u32) -> &[(u32, Event)] {
+        let start = self.next;
+        while self.next < self.events.len() && self.events[self.next].0 <= frame {
+            self.next += 1;
+        }
+        &self.events[start..self.next]
+    }
+}
+
+fn parse_line(line: &str) -> Result<(u32, Event)> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let frame = words[0].parse().context("Bad frame number")?;
+    Ok((frame, Event::parse(&words[1..])?))
+}
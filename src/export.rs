@@ -0,0 +1,94 @@
+use crate::record::write_f32;
+use crate::sim::Boid;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// File formats a frame of boids can be exported in
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Binary little-endian PLY point cloud, with each boid's heading as its normal
+    Ply,
+    /// One `x,y,z,hx,hy,hz` row per boid under a header row
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn parse(s: &str) -> Result<Self> {
+        match s {
+            "ply" => Ok(Self::Ply),
+            "csv" => Ok(Self::Csv),
+            _ => bail!("Unknown export format {:?}, expected ply or csv", s),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ply => "ply",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+/// Writes the position and heading of every boid to one file per frame, named
+/// `frame_000042.ply` and so on, for bringing a run into Blender or Houdini as a point cloud
+pub struct Exporter {
+    dir: PathBuf,
+    format: ExportFormat,
+}
+
+impl Exporter {
+    /// Export into `dir`, creating it if needed
+    pub fn new(dir: &str, format: ExportFormat) -> Result<Self> {
+        std::fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir))?;
+        Ok(Self {
+            dir: dir.into(),
+            format,
+        })
+    }
+
+    pub fn export(&self, frame: u32, boids: &[Boid]) -> Result<()> {
+        let name = format!("frame_{:06}.{}", frame, self.format.name());
+        write_boids(&self.dir.join(name), self.format, boids)
+    }
+}
+
+/// Write the position and heading of every boid to a single file, as `Exporter` does each frame
+pub fn write_boids(path: &Path, format: ExportFormat, boids: &[Boid]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    match format {
+        ExportFormat::Ply => write_ply(&mut out, boids)?,
+        ExportFormat::Csv => write_csv(&mut out, boids)?,
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn write_ply(out: &mut impl Write, boids: &[Boid]) -> Result<()> {
+    write!(
+        out,
+        "ply\nformat binary_little_endian 1.0\nelement vertex {}\n",
+        boids.len()
+    )?;
+    for property in &["x", "y", "z", "nx", "ny", "nz"] {
+        writeln!(out, "property float {}", property)?;
+    }
+    writeln!(out, "end_header")?;
+    for boid in boids {
+        for &x in boid.pos.iter().chain(boid.heading.iter()) {
+            write_f32(out, x)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_csv(out: &mut impl Write, boids: &[Boid]) -> Result<()> {
+    writeln!(out, "x,y,z,hx,hy,hz")?;
+    for boid in boids {
+        let (p, h) = (boid.pos, boid.heading);
+        writeln!(out, "{},{},{},{},{},{}", p.x, p.y, p.z, h.x, h.y, h.z)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use crate::sim::Boid;
+type Vec3 = nalgebra::Vector3<f32>;
+
+/// Boids sorted into cubic cells, for finding the boids near a point without visiting the whole
+/// flock. Cells are hashed into a table with about one bucket per boid, so the grid stays the
+/// size of the flock however far it spreads; cells that share a bucket only cost extra candidates
+pub struct Grid {
+    cell: f32,
+    /// Boid indices sorted by bucket: bucket `b` holds `order[starts[b]..starts[b + 1]]`
+    order: Vec<usize>,
+    starts: Vec<usize>,
+}
+
+impl Grid {
+    /// Counting sort of `boids` into cells of side `cell`
+    pub fn new(boids: &[Boid], cell: f32) -> Self {
+        let buckets = boids.len().next_power_of_two();
+        let keys: Vec<usize> = boids
+            .iter()
+            .map(|b| bucket(cell_of(b.pos, cell), buckets))
+            .collect();
+
+        let mut starts = vec![0; buckets + 1];
+        for &key in &keys {
+            starts[key + 1] += 1;
+        }
+        for b in 0..buckets {
+            starts[b + 1] += starts[b];
+        }
+
+        let mut next = starts.clone();
+        let mut order = vec![0; boids.len()];
+        for (idx, &key) in keys.iter().enumerate() {
+            order[next[key]] = idx;
+            next[key] += 1;
+        }
+
+        Self {
+            cell,
+            order,
+            starts,
+        }
+    }
+
+    /// Fill `out` with the boids in the 27 cells around `pos`'s. That is every boid within one
+    /// cell side of `pos`, along with some further away
+    pub fn near(&self, pos: Vec3, out: &mut Vec<usize>) {
+        out.clear();
+        let center = cell_of(pos, self.cell);
+        let buckets = self.starts.len() - 1;
+
+        // Neighboring cells may hash to the same bucket, which must only be visited once
+        let mut visited = [usize::MAX; 27];
+        let mut n_visited = 0;
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let cell = [center[0] + dx, center[1] + dy, center[2] + dz];
+                    let b = bucket(cell, buckets);
+                    if visited[..n_visited].contains(&b) {
+                        continue;
+                    }
+                    visited[n_visited] = b;
+                    n_visited += 1;
+                    out.extend_from_slice(&self.order[self.starts[b]..self.starts[b + 1]]);
+                }
+            }
+        }
+    }
+}
+
+fn cell_of(pos: Vec3, cell: f32) -> [i32; 3] {
+    let c = pos / cell;
+    [c.x.floor() as i32, c.y.floor() as i32, c.z.floor() as i32]
+}
+
+// `buckets` is a power of two, so the hash can be masked down to it
+fn bucket(cell: [i32; 3], buckets: usize) -> usize {
+    let hash = (cell[0] as u32).wrapping_mul(73_856_093)
+        ^ (cell[1] as u32).wrapping_mul(19_349_663)
+        ^ (cell[2] as u32).wrapping_mul(83_492_791);
+    hash as usize & (buckets - 1)
+}
@@ -0,0 +1,11 @@
+//! The boids simulation without any rendering, for embedding in other engines. The `fast_boids`
+//! viewer in `src/bin/viewer` is built on top of it and needs the `viewer` feature, which pulls in
+//! klystron; depend on this crate with `default-features = false` to not compile it. Cargo still
+//! reads klystron's manifest when resolving, since it's a path dependency, so `../klystron` has to
+//! be checked out next to this crate either way
+pub mod events;
+pub mod export;
+pub mod grid;
+pub mod record;
+pub mod schema;
+pub mod sim;
@@ -0,0 +1,529 @@
+use crate::sim::{
+    Accel, Attractor, Boid, Plane, Settings, Shape, Simulation, Species, SplitNormal, SplitStrategy,
+};
+use anyhow::{bail, Context, Result};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+type Vec3 = nalgebra::Vector3<f32>;
+
+/// First bytes of a recording, bumped whenever the layout changes
+const MAGIC: &[u8; 4] = b"BBF1";
+
+/// First bytes of a `Simulation::save_state()` file, bumped whenever the layout changes, which
+/// includes adding a setting
+pub(crate) const STATE_MAGIC: &[u8; 4] = b"BBS2";
+
+/// Longest name `read_name()` accepts, far past that of any enum setting
+const MAX_NAME: u32 = 64;
+
+/// Appends each frame of a run to a file: the boids, the predators and the groups, little-endian
+/// and uncompressed, at 44 bytes per boid and 48 per group. Each frame is flushed as it is
+/// written, so a run that is killed loses at most the frame it was writing
+pub struct Recorder {
+    out: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> Result<Self> {
+        Ok(Self {
+            out: create(path, MAGIC)?,
+        })
+    }
+
+    pub fn record(&mut self, sim: &Simulation) -> Result<()> {
+        write_boids(&mut self.out, sim.boids())?;
+        write_boids(&mut self.out, sim.predators())?;
+        let groups = sim.groups();
+        write_u32(&mut self.out, groups.len() as u32)?;
+        for group in &groups {
+            write_plane(&mut self.out, group)?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// One recorded frame
+pub struct Frame {
+    pub boids: Vec<Boid>,
+    pub predators: Vec<Boid>,
+    pub groups: Vec<Plane>,
+}
+
+/// Reads back the frames a `Recorder` wrote
+pub struct Replay {
+    input: BufReader<File>,
+}
+
+impl Replay {
+    pub fn open(path: &str) -> Result<Self> {
+        Ok(Self {
+            input: open(path, MAGIC, "boids recording")?,
+        })
+    }
+
+    /// The next frame, or None at the end of the recording. A frame cut off part way through, as
+    /// the last one is when the recording run was killed, counts as the end too
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        match read_frame(&mut self.input) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(io) if io.kind() == ErrorKind::UnexpectedEof => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Go back to the first frame
+    pub fn rewind(&mut self) -> Result<()> {
+        self.input.seek(SeekFrom::Start(MAGIC.len() as u64))?;
+        Ok(())
+    }
+}
+
+fn read_frame(input: &mut impl Read) -> Result<Frame> {
+    let n = read_u32(input)?;
+    let boids = read_boids(input, n)?;
+    let n = read_u32(input)?;
+    let predators = read_boids(input, n)?;
+    let n = read_u32(input)?;
+    let groups = (0..n)
+        .map(|_| read_plane(input))
+        .collect::<std::io::Result<_>>()?;
+    Ok(Frame {
+        boids,
+        predators,
+        groups,
+    })
+}
+
+/// Create the file at `path` and start it with `magic`
+pub(crate) fn create(path: &str, magic: &[u8; 4]) -> Result<BufWriter<File>> {
+    let file = File::create(path).with_context(|| format!("Creating {}", path))?;
+    let mut out = BufWriter::new(file);
+    out.write_all(magic)?;
+    Ok(out)
+}
+
+/// Open the file at `path`, failing unless it starts with `magic`. `what` names the kind of file
+/// for the error
+pub(crate) fn open(path: &str, magic: &[u8; 4], what: &str) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("Opening {}", path))?;
+    let mut input = BufReader::new(file);
+    let mut found = [0; 4];
+    input.read_exact(&mut found)?;
+    if &found != magic {
+        bail!("{} isn't a {}", path, what);
+    }
+    Ok(input)
+}
+
+pub(crate) fn write_boids(out: &mut impl Write, boids: &[Boid]) -> Result<()> {
+    write_u32(out, boids.len() as u32)?;
+    for boid in boids {
+        write_vec3(out, boid.pos)?;
+        write_vec3(out, boid.heading)?;
+        write_u32(out, boid.species)?;
+        write_f32(out, boid.roll)?;
+        write_f32(out, boid.speed)?;
+        write_u32(out, boid.mask)?;
+        write_u32(out, boid.level)?;
+    }
+    Ok(())
+}
+
+/// Reads `n` boids as `write_boids()` wrote them, after their count. Counts come from the file,
+/// so nothing is reserved up front: a corrupt one runs out of data instead of memory
+pub(crate) fn read_boids(input: &mut impl Read, n: u32) -> Result<Vec<Boid>> {
+    let mut boids = Vec::new();
+    for _ in 0..n {
+        boids.push(Boid {
+            pos: read_vec3(input)?,
+            heading: read_vec3(input)?,
+            species: read_u32(input)?,
+            roll: read_f32(input)?,
+            speed: read_f32(input)?,
+            mask: read_u32(input)?,
+            level: read_u32(input)?,
+        });
+    }
+    Ok(boids)
+}
+
+/// Writes every field of `settings`, in declaration order
+pub(crate) fn write_settings(out: &mut impl Write, settings: &Settings) -> Result<()> {
+    // Destructured without `..` so that a new field fails to compile until it is saved here
+    let Settings {
+        n_boids,
+        tree_depth,
+        speed,
+        time_scale,
+        speed_min,
+        speed_max,
+        acceleration,
+        drag,
+        dist_thresh,
+        cohere,
+        steer,
+        parallel,
+        align_falloff,
+        max_force,
+        separation,
+        separation_radius,
+        fov_degrees,
+        max_turn_rate,
+        noise,
+        max_neighbor_groups,
+        group_smoothing,
+        nan_recovery,
+        max_broken_fraction,
+        split_strategy,
+        split_normal,
+        accel,
+        grid_cell,
+        neighbors,
+        locked_axes,
+        tree_rebuild_threshold,
+        debug_checks,
+        deterministic_reduce,
+        history_frames,
+        debug_forces,
+        hold_position,
+        num_predators,
+        predator_speed,
+        predator_turn,
+        predator_sight,
+        fear_radius,
+        flee,
+        obstacles,
+        bounds,
+        contain,
+        attractors,
+        wind,
+        flow,
+        flow_scale,
+        avoid_distance,
+        avoid,
+        species,
+        seed,
+    } = settings;
+
+    write_u64(out, *n_boids as u64)?;
+    write_u32(out, *tree_depth)?;
+    for &x in &[
+        *speed,
+        *time_scale,
+        *speed_min,
+        *speed_max,
+        *acceleration,
+        *drag,
+        *dist_thresh,
+        *cohere,
+        *steer,
+        *parallel,
+        *align_falloff,
+        *max_force,
+        *separation,
+        *separation_radius,
+        *fov_degrees,
+        *max_turn_rate,
+        *noise,
+    ] {
+        write_f32(out, x)?;
+    }
+    write_u64(out, *max_neighbor_groups as u64)?;
+    write_f32(out, *group_smoothing)?;
+    write_bool(out, *nan_recovery)?;
+    write_f32(out, *max_broken_fraction)?;
+    write_name(out, split_strategy.name())?;
+    write_name(out, split_normal.name())?;
+    write_name(out, accel.name())?;
+    write_f32(out, *grid_cell)?;
+    write_u64(out, *neighbors as u64)?;
+    for &locked in locked_axes {
+        write_bool(out, locked)?;
+    }
+    write_f32(out, *tree_rebuild_threshold)?;
+    write_bool(out, *debug_checks)?;
+    write_bool(out, *deterministic_reduce)?;
+    write_u64(out, *history_frames as u64)?;
+    write_bool(out, *debug_forces)?;
+    write_bool(out, *hold_position)?;
+    write_u64(out, *num_predators as u64)?;
+    for &x in &[
+        *predator_speed,
+        *predator_turn,
+        *predator_sight,
+        *fear_radius,
+        *flee,
+    ] {
+        write_f32(out, x)?;
+    }
+    write_u32(out, obstacles.len() as u32)?;
+    for obstacle in obstacles {
+        write_shape(out, obstacle)?;
+    }
+    write_bool(out, bounds.is_some())?;
+    if let Some(bounds) = bounds {
+        write_shape(out, bounds)?;
+    }
+    write_f32(out, *contain)?;
+    write_u32(out, attractors.len() as u32)?;
+    for attractor in attractors {
+        write_vec3(out, attractor.pos)?;
+        write_f32(out, attractor.strength)?;
+    }
+    write_vec3(out, *wind)?;
+    for &x in &[*flow, *flow_scale, *avoid_distance, *avoid] {
+        write_f32(out, x)?;
+    }
+    write_u32(out, species.len() as u32)?;
+    for s in species {
+        for &x in &[s.speed, s.cohere, s.steer, s.parallel] {
+            write_f32(out, x)?;
+        }
+    }
+    write_bool(out, seed.is_some())?;
+    write_u64(out, seed.unwrap_or(0))?;
+    Ok(())
+}
+
+/// Reads settings as `write_settings()` wrote them
+pub(crate) fn read_settings(input: &mut impl Read) -> Result<Settings> {
+    // Fields are read in the order they're written out in, which is the order they're listed in
+    Ok(Settings {
+        n_boids: read_u64(input)? as usize,
+        tree_depth: read_u32(input)?,
+        speed: read_f32(input)?,
+        time_scale: read_f32(input)?,
+        speed_min: read_f32(input)?,
+        speed_max: read_f32(input)?,
+        acceleration: read_f32(input)?,
+        drag: read_f32(input)?,
+        dist_thresh: read_f32(input)?,
+        cohere: read_f32(input)?,
+        steer: read_f32(input)?,
+        parallel: read_f32(input)?,
+        align_falloff: read_f32(input)?,
+        max_force: read_f32(input)?,
+        separation: read_f32(input)?,
+        separation_radius: read_f32(input)?,
+        fov_degrees: read_f32(input)?,
+        max_turn_rate: read_f32(input)?,
+        noise: read_f32(input)?,
+        max_neighbor_groups: read_u64(input)? as usize,
+        group_smoothing: read_f32(input)?,
+        nan_recovery: read_bool(input)?,
+        max_broken_fraction: read_f32(input)?,
+        split_strategy: SplitStrategy::parse(&read_name(input)?)?,
+        split_normal: SplitNormal::parse(&read_name(input)?)?,
+        accel: Accel::parse(&read_name(input)?)?,
+        grid_cell: read_f32(input)?,
+        neighbors: read_u64(input)? as usize,
+        locked_axes: [read_bool(input)?, read_bool(input)?, read_bool(input)?],
+        tree_rebuild_threshold: read_f32(input)?,
+        debug_checks: read_bool(input)?,
+        deterministic_reduce: read_bool(input)?,
+        history_frames: read_u64(input)? as usize,
+        debug_forces: read_bool(input)?,
+        hold_position: read_bool(input)?,
+        num_predators: read_u64(input)? as usize,
+        predator_speed: read_f32(input)?,
+        predator_turn: read_f32(input)?,
+        predator_sight: read_f32(input)?,
+        fear_radius: read_f32(input)?,
+        flee: read_f32(input)?,
+        obstacles: {
+            let n = read_u32(input)?;
+            (0..n).map(|_| read_shape(input)).collect::<Result<_>>()?
+        },
+        bounds: match read_bool(input)? {
+            true => Some(read_shape(input)?),
+            false => None,
+        },
+        contain: read_f32(input)?,
+        attractors: {
+            let n = read_u32(input)?;
+            let mut attractors = Vec::new();
+            for _ in 0..n {
+                attractors.push(Attractor {
+                    pos: read_vec3(input)?,
+                    strength: read_f32(input)?,
+                });
+            }
+            attractors
+        },
+        wind: read_vec3(input)?,
+        flow: read_f32(input)?,
+        flow_scale: read_f32(input)?,
+        avoid_distance: read_f32(input)?,
+        avoid: read_f32(input)?,
+        species: {
+            let n = read_u32(input)?;
+            let mut species = Vec::new();
+            for _ in 0..n {
+                species.push(Species {
+                    speed: read_f32(input)?,
+                    cohere: read_f32(input)?,
+                    steer: read_f32(input)?,
+                    parallel: read_f32(input)?,
+                });
+            }
+            species
+        },
+        seed: {
+            let some = read_bool(input)?;
+            let seed = read_u64(input)?;
+            match some {
+                true => Some(seed),
+                false => None,
+            }
+        },
+    })
+}
+
+fn write_shape(out: &mut impl Write, shape: &Shape) -> Result<()> {
+    match shape {
+        Shape::Sphere { center, radius } => {
+            write_u32(out, 0)?;
+            write_vec3(out, *center)?;
+            write_f32(out, *radius)?;
+        }
+        Shape::Box { min, max } => {
+            write_u32(out, 1)?;
+            write_vec3(out, *min)?;
+            write_vec3(out, *max)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_shape(input: &mut impl Read) -> Result<Shape> {
+    Ok(match read_u32(input)? {
+        0 => Shape::Sphere {
+            center: read_vec3(input)?,
+            radius: read_f32(input)?,
+        },
+        1 => Shape::Box {
+            min: read_vec3(input)?,
+            max: read_vec3(input)?,
+        },
+        tag => bail!("Unknown shape tag {}", tag),
+    })
+}
+
+pub(crate) fn write_plane(out: &mut impl Write, plane: &Plane) -> Result<()> {
+    write_vec3(out, plane.pos)?;
+    write_vec3(out, plane.normal)?;
+    write_vec3(out, plane.heading)?;
+    write_u32(out, plane.count)?;
+    write_u32(out, plane.id)?;
+    write_u32(out, plane.species)?;
+    Ok(())
+}
+
+pub(crate) fn read_plane(input: &mut impl Read) -> std::io::Result<Plane> {
+    Ok(Plane {
+        pos: read_vec3(input)?,
+        normal: read_vec3(input)?,
+        heading: read_vec3(input)?,
+        count: read_u32(input)?,
+        id: read_u32(input)?,
+        species: read_u32(input)?,
+    })
+}
+
+// The seed, stream and word position pin down where a ChaCha generator is in its sequence, so
+// restoring them carries on with exactly the numbers the saved one would have produced next
+pub(crate) fn write_rng(out: &mut impl Write, rng: &ChaCha12Rng) -> std::io::Result<()> {
+    out.write_all(&rng.get_seed())?;
+    write_u64(out, rng.get_stream())?;
+    out.write_all(&rng.get_word_pos().to_le_bytes())
+}
+
+pub(crate) fn read_rng(input: &mut impl Read) -> std::io::Result<ChaCha12Rng> {
+    let mut seed = [0; 32];
+    input.read_exact(&mut seed)?;
+    let mut rng = ChaCha12Rng::from_seed(seed);
+    rng.set_stream(read_u64(input)?);
+    let mut word_pos = [0; 16];
+    input.read_exact(&mut word_pos)?;
+    rng.set_word_pos(u128::from_le_bytes(word_pos));
+    Ok(rng)
+}
+
+pub(crate) fn write_u32(out: &mut impl Write, value: u32) -> std::io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_u64(out: &mut impl Write, value: u64) -> std::io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_bool(out: &mut impl Write, value: bool) -> std::io::Result<()> {
+    out.write_all(&[value as u8])
+}
+
+// Enum settings are stored by name, so reordering their variants doesn't break old files
+fn write_name(out: &mut impl Write, name: &str) -> std::io::Result<()> {
+    write_u32(out, name.len() as u32)?;
+    out.write_all(name.as_bytes())
+}
+
+pub(crate) fn write_f32(out: &mut impl Write, value: f32) -> std::io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+pub(crate) fn write_vec3(out: &mut impl Write, v: Vec3) -> std::io::Result<()> {
+    v.iter().try_for_each(|&x| write_f32(out, x))
+}
+
+pub(crate) fn read_u32(input: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_u64(input: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn read_bool(input: &mut impl Read) -> Result<bool> {
+    let mut byte = [0];
+    input.read_exact(&mut byte)?;
+    match byte[0] {
+        0 => Ok(false),
+        1 => Ok(true),
+        b => bail!("Expected a bool, found {}", b),
+    }
+}
+
+fn read_name(input: &mut impl Read) -> Result<String> {
+    let len = read_u32(input)?;
+    if len > MAX_NAME {
+        bail!(
+            "Expected a name of at most {} bytes, found {}",
+            MAX_NAME,
+            len
+        );
+    }
+    let mut bytes = vec![0; len as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+pub(crate) fn read_f32(input: &mut impl Read) -> std::io::Result<f32> {
+    Ok(f32::from_bits(read_u32(input)?))
+}
+
+pub(crate) fn read_vec3(input: &mut impl Read) -> std::io::Result<Vec3> {
+    let mut v = Vec3::zeros();
+    for x in v.iter_mut() {
+        *x = read_f32(input)?;
+    }
+    Ok(v)
+}
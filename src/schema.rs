@@ -0,0 +1,191 @@
+use crate::sim::{Settings, MAX_TREE_DEPTH};
+use anyhow::{Context, Result};
+
+/// Read settings from a JSON object with the fields of `Settings`, as `schema()` describes it.
+/// Fields left out keep their defaults, and unknown fields are an error
+pub fn parse(json: &str) -> Result<Settings> {
+    let settings: Settings = serde_json::from_str(json)?;
+    settings.validate()?;
+    Ok(settings)
+}
+
+/// `parse()` the JSON file at `path`
+pub fn load(path: &str) -> Result<Settings> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("Reading {}", path))?;
+    parse(&json).with_context(|| format!("Loading settings from {}", path))
+}
+
+/// JSON Schema of the settings files `load()` reads: every field of `Settings` with its type,
+/// default and the bounds that `Settings::validate()` enforces, for tools that generate or check
+/// settings
+pub fn schema() -> String {
+    // Destructured without `..` so that a new field fails to compile until it is described here
+    let Settings {
+        n_boids,
+        tree_depth,
+        speed: _,
+        time_scale: _,
+        speed_min: _,
+        speed_max: _,
+        acceleration: _,
+        drag: _,
+        dist_thresh: _,
+        cohere: _,
+        steer: _,
+        parallel: _,
+        align_falloff: _,
+        max_force: _,
+        separation: _,
+        separation_radius: _,
+        fov_degrees: _,
+        max_turn_rate: _,
+        noise: _,
+        max_neighbor_groups,
+        group_smoothing: _,
+        nan_recovery,
+        max_broken_fraction,
+        split_strategy,
+        split_normal,
+        accel,
+        grid_cell: _,
+        neighbors,
+        locked_axes,
+        tree_rebuild_threshold: _,
+        debug_checks,
+        deterministic_reduce,
+        history_frames,
+        debug_forces,
+        hold_position,
+        num_predators,
+        predator_speed: _,
+        predator_turn: _,
+        predator_sight: _,
+        fear_radius: _,
+        flee: _,
+        obstacles: _,
+        bounds: _,
+        contain: _,
+        attractors: _,
+        wind,
+        flow: _,
+        flow_scale: _,
+        avoid_distance: _,
+        avoid: _,
+        species: _,
+        seed: _,
+    } = Settings::default();
+
+    let mut properties = vec![
+        // validate() asks for a boid per species, which a schema can only state for the default
+        // species list. The description carries the rest
+        format!(
+            r#""n_boids": {{"type": "integer", "default": {}, "minimum": {}, "description": "At least one per species, so at least the length of species when it isn't empty"}}"#,
+            n_boids,
+            Settings::default().species_count()
+        ),
+        integer(
+            "tree_depth",
+            tree_depth as usize,
+            0,
+            Some(MAX_TREE_DEPTH as usize),
+        ),
+        boolean("nan_recovery", nan_recovery),
+        format!(
+            r#""max_broken_fraction": {{"type": "number", "default": {}, "minimum": 0, "maximum": 1}}"#,
+            max_broken_fraction
+        ),
+        format!(
+            r#""split_strategy": {{"enum": ["mean", "median"], "default": "{}"}}"#,
+            split_strategy.name()
+        ),
+        format!(
+            r#""split_normal": {{"enum": ["random", "heading", "pca"], "default": "{}"}}"#,
+            split_normal.name()
+        ),
+        format!(
+            r#""accel": {{"enum": ["tree", "grid"], "default": "{}"}}"#,
+            accel.name()
+        ),
+        format!(
+            r#""locked_axes": {{"type": "array", "items": {{"type": "boolean"}}, "minItems": 3, "maxItems": 3, "default": {:?}}}"#,
+            locked_axes
+        ),
+        boolean("debug_checks", debug_checks),
+        boolean("deterministic_reduce", deterministic_reduce),
+        integer("history_frames", history_frames, 0, None),
+        boolean("debug_forces", debug_forces),
+        boolean("hold_position", hold_position),
+        integer("num_predators", num_predators, 0, None),
+        integer("max_neighbor_groups", max_neighbor_groups, 0, None),
+        integer("neighbors", neighbors, 0, None),
+    ];
+
+    let defaults = Settings::default();
+    for name in Settings::WEIGHTS {
+        let default = defaults.weight(name).expect("WEIGHTS are all valid names");
+        let bounds = match *name {
+            "group_smoothing" => r#""minimum": 0, "exclusiveMaximum": 1"#,
+            "drag" => r#""minimum": 0, "maximum": 1"#,
+            "grid_cell" | "flow_scale" => r#""exclusiveMinimum": 0"#,
+            "fov_degrees" => r#""minimum": 0, "maximum": 360"#,
+            _ => r#""minimum": 0"#,
+        };
+        properties.push(format!(
+            r#""{}": {{"type": "number", "default": {}, {}}}"#,
+            name, default, bounds
+        ));
+    }
+
+    properties.push(
+        r##""obstacles": {"type": "array", "default": [], "items": {"$ref": "#/$defs/shape"}}"##
+            .to_string(),
+    );
+    properties.push(
+        r##""bounds": {"oneOf": [{"type": "null"}, {"$ref": "#/$defs/shape"}], "default": null}"##
+            .to_string(),
+    );
+    properties.push(
+        r##""attractors": {"type": "array", "default": [], "items": {"type": "object", "additionalProperties": false, "required": ["pos", "strength"], "properties": {"pos": {"$ref": "#/$defs/vec3"}, "strength": {"type": "number"}}}}"##
+            .to_string(),
+    );
+    properties.push(format!(
+        r##""wind": {{"$ref": "#/$defs/vec3", "default": {:?}}}"##,
+        wind.as_slice()
+    ));
+    properties.push(
+        r#""species": {"type": "array", "default": [], "items": {"type": "object", "additionalProperties": false, "required": ["speed", "cohere", "steer", "parallel"], "properties": {"speed": {"type": "number", "minimum": 0}, "cohere": {"type": "number", "minimum": 0}, "steer": {"type": "number", "minimum": 0}, "parallel": {"type": "number", "minimum": 0}}}}"#
+            .to_string(),
+    );
+
+    properties.push(
+        r#""seed": {"oneOf": [{"type": "null"}, {"type": "integer", "minimum": 0}], "default": null}"#
+            .to_string(),
+    );
+
+    format!(
+        "{{\n  \"$schema\": \"https://json-schema.org/draft/2020-12/schema\",\n  \"title\": \"Settings\",\n  \"type\": \"object\",\n  \"additionalProperties\": false,\n  \"$defs\": {},\n  \"properties\": {{\n    {}\n  }}\n}}",
+        DEFS,
+        properties.join(",\n    ")
+    )
+}
+
+/// Shared definitions, referred to as `#/$defs/NAME`
+const DEFS: &str = r##"{"vec3": {"type": "array", "items": {"type": "number"}, "minItems": 3, "maxItems": 3}, "shape": {"oneOf": [{"type": "object", "additionalProperties": false, "required": ["center", "radius"], "properties": {"center": {"$ref": "#/$defs/vec3"}, "radius": {"type": "number", "minimum": 0}}}, {"type": "object", "additionalProperties": false, "required": ["min", "max"], "properties": {"min": {"$ref": "#/$defs/vec3"}, "max": {"$ref": "#/$defs/vec3"}}}]}}"##;
+
+fn integer(name: &str, default: usize, min: usize, max: Option<usize>) -> String {
+    let max = match max {
+        Some(max) => format!(r#", "maximum": {}"#, max),
+        None => String::new(),
+    };
+    format!(
+        r#""{}": {{"type": "integer", "default": {}, "minimum": {}{}}}"#,
+        name, default, min, max
+    )
+}
+
+fn boolean(name: &str, default: bool) -> String {
+    format!(
+        r#""{}": {{"type": "boolean", "default": {}}}"#,
+        name, default
+    )
+}
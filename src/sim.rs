@@ -1,51 +1,1648 @@
+use crate::grid::Grid;
+use crate::record::{self, Frame};
+use anyhow::{bail, Result};
 use rand::distributions::{Distribution, Uniform};
+use rand::SeedableRng;
+use rand_chacha::ChaCha12Rng;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 type Vec3 = nalgebra::Vector3<f32>;
 
+/// Half the side length of the cube boids spawn in
+const SPAWN_SCALE: f32 = 10.;
+
+/// How far boids bank into a turn: turning by 0.1 radians in a step rolls a boid by 45 degrees
+const BANK: f32 = 10.;
+
+/// Deepest tree `Settings::validate()` accepts. Each species' tree holds `2^(depth + 1) - 1`
+/// nodes, about two million at this depth, which is a leaf per boid for a million boids
+pub const MAX_TREE_DEPTH: u32 = 20;
+
+/// Simulation parameters.
+///
+/// Each step a boid's steering force is built in this order:
+/// 1. `cohere` blends between swirling around the neighbor centroid and heading into it, more so
+///    the closer than `dist_thresh` the neighbors are on average
+/// 2. That blend is weighted by `steer`, and the average neighbor heading (weighted by distance
+///    according to `align_falloff`) by `parallel`
+/// 3. Predators closer than `fear_radius` push the boid away, weighted by `flee`, and obstacles
+///    closer than `avoid_distance` do the same, weighted by `avoid`. Boids outside the
+///    `bounds` turn back towards the middle, weighted by `contain`, and each attractor pulls
+///    the boid towards it by its strength
+/// 4. The sum is clamped to `max_force`, whatever terms contributed to it
+/// 5. The force is added to the heading, which is renormalized and moved along by `speed`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub n_boids: usize,
+    pub tree_depth: u32,
+    /// Cruising speed, which each boid's own speed settles back towards
+    pub speed: f32,
+    /// Multiplies how far boids, predators and the air move each step, to slow the whole
+    /// simulation down or speed it up. Turning isn't scaled
+    pub time_scale: f32,
+    /// Bounds on each boid's own speed
+    pub speed_min: f32,
+    pub speed_max: f32,
+    /// How much of the steering force along a boid's heading goes into speeding it up, or into
+    /// slowing it down when the force points backwards
+    pub acceleration: f32,
+    /// Fraction of the gap to `speed` a boid closes each step, up to 1. At 1, with no
+    /// acceleration, every boid flies at exactly `speed`
+    pub drag: f32,
+    pub dist_thresh: f32,
+    pub cohere: f32,
+    pub steer: f32,
+    pub parallel: f32,
+    /// Each group's heading counts towards alignment with weight `distance^-align_falloff`. 0 weighs
+    /// all groups equally
+    pub align_falloff: f32,
+    pub max_force: f32,
+    /// How hard boids push away from neighbors closer than `separation_radius`, growing from 0 at
+    /// the radius to this when touching. Keeps cohesion from packing boids into one point. Group
+    /// centroids seldom come this close, so it mostly matters with `Accel::Grid` or `neighbors`
+    pub separation: f32,
+    pub separation_radius: f32,
+    /// Width in degrees of the cone ahead of each boid that it sees neighbors in. Neighbors behind
+    /// it are ignored. 360 sees all around
+    pub fov_degrees: f32,
+    /// Most a boid's heading can turn in one step, in radians. Pi or more is unlimited
+    pub max_turn_rate: f32,
+    /// Strength of a random push on each boid, different for every boid and every step, that
+    /// keeps the flock from settling into perfectly smooth motion
+    pub noise: f32,
+    /// Each boid only looks at this many of the nearest leaf groups, bounding the work per boid
+    /// when a deep tree makes many leaves. 0 looks at all of them
+    pub max_neighbor_groups: usize,
+    /// How much of last step's leaf groups to blend into this step's, in `[0, 1)`. 0 disables
+    /// smoothing. Each leaf is blended with whichever of last step's leaves had the closest
+    /// centroid, since split planes change from step to step and a leaf's index says nothing
+    /// about where it is. The pairing compares every leaf with every other, so it gets slow
+    /// past a tree_depth of about 10
+    pub group_smoothing: f32,
+    /// Respawn boids whose position or heading stops being finite
+    pub nan_recovery: bool,
+    /// Fraction of the flock that may break in a single step before recovery gives up and `step()`
+    /// returns an error, since that points at bad settings rather than bad luck
+    pub max_broken_fraction: f32,
+    /// Where along its normal each node's plane is placed
+    pub split_strategy: SplitStrategy,
+    /// Which way each node's plane faces
+    pub split_normal: SplitNormal,
+    /// What boids steer by
+    pub accel: Accel,
+    /// Side of a grid cell with `Accel::Grid`. Boids steer by the others within this distance
+    pub grid_cell: f32,
+    /// Steer each boid by only its K nearest boids instead of by groups. They are looked for
+    /// among the boids near it in the grid with `Accel::Grid`, and otherwise among the boids in
+    /// its own tree leaf, which misses neighbors just across a plane. 0 disables it
+    pub neighbors: usize,
+    /// Axes (x, y, z) along which nothing moves. Boids spawn at 0 on locked axes and stay there
+    pub locked_axes: [bool; 3],
+    /// Reuse the last partition tree until a corner of the flock's bounding box has moved further
+    /// than this since it was built. Cheaper, but groups lag behind the boids in between. 0
+    /// rebuilds every step
+    pub tree_rebuild_threshold: f32,
+    /// Check after each tree build that every boid landed in exactly one leaf
+    pub debug_checks: bool,
+    /// Sum each group one boid at a time in index order instead of pairwise. Slower to reduce,
+    /// but group centroids and headings then match a plain loop over the boids bit for bit,
+    /// which makes comparisons against other implementations exact. The pairwise sum is
+    /// deterministic too, it just rounds differently
+    pub deterministic_reduce: bool,
+    /// Number of past steps kept for `seek()` and `step_back()`. Each one costs a full copy of
+    /// the flock, `n_boids * size_of::<Boid>()` bytes (44 per boid), so 0 disables it
+    pub history_frames: usize,
+    /// Keep each boid's steering force from the last step, see `Simulation::forces()`
+    pub debug_forces: bool,
+    /// Subtract each species' mean heading from the movement of its boids so the flock as a whole
+    /// stays put. Headings, and so flocking, are unaffected
+    pub hold_position: bool,
+    pub num_predators: usize,
+    pub predator_speed: f32,
+    /// How hard predators turn towards their target each step
+    pub predator_turn: f32,
+    /// Predators chase the nearest group within this distance, and wander when there is none
+    pub predator_sight: f32,
+    pub fear_radius: f32,
+    pub flee: f32,
+    /// Solid shapes boids steer around
+    pub obstacles: Vec<Shape>,
+    /// Boids that leave this volume turn back towards its center, harder the further out they
+    /// are up to `contain` at `avoid_distance` outside. None lets them fly off forever
+    pub bounds: Option<Shape>,
+    pub contain: f32,
+    /// Points that pull every boid towards them. Can be changed while running with
+    /// `Simulation::set_attractors()`
+    pub attractors: Vec<Attractor>,
+    /// Air moving at this velocity per step carries boids and predators along, whichever way
+    /// they head
+    pub wind: Vec3,
+    /// Peak speed of swirling air on top of `wind`, with eddies `flow_scale` across. The flow
+    /// is divergence-free, so it stirs the flock without piling it up anywhere
+    pub flow: f32,
+    pub flow_scale: f32,
+    /// Boids start turning away from an obstacle this far from its surface
+    pub avoid_distance: f32,
+    /// How hard boids turn away from obstacles, growing from 0 at `avoid_distance` to this at the
+    /// surface
+    pub avoid: f32,
+    /// Flocks sharing the space that each only flock with their own kind. Boids are divided
+    /// evenly between them by index, and each species gets its own partition tree. Empty means a
+    /// single species using the weights above
+    pub species: Vec<Species>,
+    /// Seed for every random choice the simulation makes: the starting flock, split plane
+    /// normals, wandering predators and respawns. Two simulations with the same seed and settings
+    /// step bit for bit alike on the same build. None seeds from the OS
+    pub seed: Option<u64>,
+}
+
+/// How a tree node places the plane that splits its boids
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitStrategy {
+    /// Through the centroid of the node's boids. Cheap, but a clustered flock leaves most boids
+    /// on one side
+    Mean,
+    /// At the median of the node's boids along the plane normal, so both children get about as
+    /// many boids
+    Median,
+}
+
+impl SplitStrategy {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "mean" => Self::Mean,
+            "median" => Self::Median,
+            _ => bail!("Unknown split strategy {:?}, expected mean or median", s),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Mean => "mean",
+            Self::Median => "median",
+        }
+    }
+}
+
+/// How a tree node orients the plane that splits its boids
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitNormal {
+    /// A fresh random direction every step
+    Random,
+    /// The node's mean heading, cutting its boids into those ahead and those behind. Falls back
+    /// to a random direction when the headings cancel out
+    Heading,
+    /// The direction the node's boids are most spread out along, so the plane cuts the node
+    /// across its longest extent. Falls back to a random direction when they all sit at one point
+    Pca,
+}
+
+impl SplitNormal {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "random" => Self::Random,
+            "heading" => Self::Heading,
+            "pca" => Self::Pca,
+            _ => bail!(
+                "Unknown split normal {:?}, expected random, heading or pca",
+                s
+            ),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Random => "random",
+            Self::Heading => "heading",
+            Self::Pca => "pca",
+        }
+    }
+}
+
+/// What each boid looks at to decide where to steer
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Accel {
+    /// The leaf groups of the partition tree
+    Tree,
+    /// Every other boid of its species within `grid_cell`, found through a uniform grid. The tree
+    /// is still built, for predators and `groups()`, so pair this with a shallow `tree_depth`
+    Grid,
+}
+
+impl Accel {
+    pub fn parse(s: &str) -> Result<Self> {
+        Ok(match s {
+            "tree" => Self::Tree,
+            "grid" => Self::Grid,
+            _ => bail!("Unknown accel {:?}, expected tree or grid", s),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Tree => "tree",
+            Self::Grid => "grid",
+        }
+    }
+}
+
+/// A point the flock is drawn to
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Attractor {
+    pub pos: Vec3,
+    /// Pull on each boid, the same at any distance. Negative pushes boids away instead
+    pub strength: f32,
+}
+
+/// A volume, used for obstacles and bounds. In JSON a shape is told apart by its fields alone
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Shape {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    /// Axis-aligned box between two corners
+    Box {
+        min: Vec3,
+        max: Vec3,
+    },
+}
+
+impl Shape {
+    /// Center of the volume
+    pub fn center(&self) -> Vec3 {
+        match self {
+            Shape::Sphere { center, .. } => *center,
+            Shape::Box { min, max } => (min + max) / 2.,
+        }
+    }
+
+    /// Signed distance from `pos` to the surface, negative inside, and the outward direction of
+    /// the nearest point of the surface
+    pub fn surface(&self, pos: Vec3) -> (f32, Vec3) {
+        match self {
+            Shape::Sphere { center, radius } => {
+                let offset = pos - center;
+                let dist = offset.magnitude();
+                let normal = offset.try_normalize(1e-6).unwrap_or_else(Vec3::y);
+                (dist - radius, normal)
+            }
+            Shape::Box { min, max } => {
+                let closest = pos.sup(min).inf(max);
+                let offset = pos - closest;
+                if offset != Vec3::zeros() {
+                    return (offset.magnitude(), offset.normalize());
+                }
+
+                // Inside, so leave through the nearest face
+                let mut best = (f32::INFINITY, Vec3::zeros());
+                for axis in 0..3 {
+                    let faces = [(pos[axis] - min[axis], -1.), (max[axis] - pos[axis], 1.)];
+                    for (depth, sign) in faces {
+                        if depth < best.0 {
+                            best = (depth, Vec3::ith(axis, sign));
+                        }
+                    }
+                }
+                (-best.0, best.1)
+            }
+        }
+    }
+}
+
+/// Weights one species flocks with in place of the ones in `Settings`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Species {
+    pub speed: f32,
+    pub cohere: f32,
+    pub steer: f32,
+    pub parallel: f32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            n_boids: 1 << 14,
+            tree_depth: 5,
+            speed: 0.04,
+            time_scale: 1.,
+            speed_min: 0.,
+            speed_max: 1.,
+            acceleration: 0.,
+            drag: 1.,
+            dist_thresh: 0.5,
+            cohere: 1.,
+            steer: 0.12,
+            parallel: 0.12,
+            align_falloff: 1.,
+            max_force: 0.25,
+            separation: 0.,
+            separation_radius: 0.3,
+            fov_degrees: 360.,
+            max_turn_rate: std::f32::consts::PI,
+            noise: 0.,
+            max_neighbor_groups: 0,
+            group_smoothing: 0.,
+            nan_recovery: false,
+            max_broken_fraction: 0.1,
+            split_strategy: SplitStrategy::Mean,
+            split_normal: SplitNormal::Random,
+            accel: Accel::Tree,
+            grid_cell: 1.,
+            neighbors: 0,
+            locked_axes: [false; 3],
+            tree_rebuild_threshold: 0.,
+            debug_checks: false,
+            deterministic_reduce: false,
+            history_frames: 0,
+            debug_forces: false,
+            hold_position: false,
+            num_predators: 0,
+            predator_speed: 0.06,
+            predator_turn: 0.1,
+            predator_sight: 10.,
+            fear_radius: 2.,
+            flee: 0.5,
+            obstacles: Vec::new(),
+            bounds: None,
+            contain: 0.2,
+            attractors: Vec::new(),
+            wind: Vec3::zeros(),
+            flow: 0.,
+            flow_scale: 10.,
+            avoid_distance: 2.,
+            avoid: 0.5,
+            species: Vec::new(),
+            seed: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Number of species, at least 1
+    pub fn species_count(&self) -> usize {
+        self.species.len().max(1)
+    }
+
+    /// These settings with species `s`'s weights swapped in
+    fn for_species(&self, s: usize) -> Settings {
+        let mut settings = self.clone();
+        if let Some(species) = self.species.get(s) {
+            settings.speed = species.speed;
+            settings.cohere = species.cohere;
+            settings.steer = species.steer;
+            settings.parallel = species.parallel;
+        }
+        settings
+    }
+
+    /// Check every weight as `set_weight()` would, including each species' weights, and that the
+    /// tree isn't deeper than boid masks can record
+    pub fn validate(&self) -> Result<()> {
+        let mut check = Settings::default();
+        for name in Self::WEIGHTS {
+            check.set_weight(name, self.weight(name)?)?;
+        }
+        for species in &self.species {
+            check.set_weight("speed", species.speed)?;
+            check.set_weight("cohere", species.cohere)?;
+            check.set_weight("steer", species.steer)?;
+            check.set_weight("parallel", species.parallel)?;
+        }
+        if !self.wind.iter().all(|x| x.is_finite()) {
+            bail!("Wind {:?} isn't finite", self.wind);
+        }
+        if self.speed_min > self.speed_max {
+            bail!(
+                "speed_min {} is above speed_max {}",
+                self.speed_min,
+                self.speed_max
+            );
+        }
+        if self.n_boids < self.species_count() {
+            bail!(
+                "n_boids must be at least one per species, got {} for {} species",
+                self.n_boids,
+                self.species_count()
+            );
+        }
+        if !(0. ..=1.).contains(&self.max_broken_fraction) {
+            bail!(
+                "max_broken_fraction must be in [0, 1], got {}",
+                self.max_broken_fraction
+            );
+        }
+        if self.tree_depth > MAX_TREE_DEPTH {
+            bail!(
+                "tree_depth can be at most {}, got {}",
+                MAX_TREE_DEPTH,
+                self.tree_depth
+            );
+        }
+        Ok(())
+    }
+
+    /// Names accepted by `weight()` and `set_weight()`
+    pub const WEIGHTS: &'static [&'static str] = &[
+        "speed",
+        "time_scale",
+        "speed_min",
+        "speed_max",
+        "acceleration",
+        "drag",
+        "dist_thresh",
+        "cohere",
+        "steer",
+        "parallel",
+        "align_falloff",
+        "max_force",
+        "separation",
+        "separation_radius",
+        "fov_degrees",
+        "max_turn_rate",
+        "noise",
+        "group_smoothing",
+        "tree_rebuild_threshold",
+        "predator_speed",
+        "predator_turn",
+        "predator_sight",
+        "fear_radius",
+        "flee",
+        "avoid_distance",
+        "avoid",
+        "contain",
+        "grid_cell",
+        "flow",
+        "flow_scale",
+    ];
+
+    pub fn weight(&self, name: &str) -> Result<f32> {
+        Ok(match name {
+            "speed" => self.speed,
+            "time_scale" => self.time_scale,
+            "speed_min" => self.speed_min,
+            "speed_max" => self.speed_max,
+            "acceleration" => self.acceleration,
+            "drag" => self.drag,
+            "dist_thresh" => self.dist_thresh,
+            "cohere" => self.cohere,
+            "steer" => self.steer,
+            "parallel" => self.parallel,
+            "align_falloff" => self.align_falloff,
+            "max_force" => self.max_force,
+            "separation" => self.separation,
+            "separation_radius" => self.separation_radius,
+            "fov_degrees" => self.fov_degrees,
+            "max_turn_rate" => self.max_turn_rate,
+            "noise" => self.noise,
+            "group_smoothing" => self.group_smoothing,
+            "tree_rebuild_threshold" => self.tree_rebuild_threshold,
+            "predator_speed" => self.predator_speed,
+            "predator_turn" => self.predator_turn,
+            "predator_sight" => self.predator_sight,
+            "fear_radius" => self.fear_radius,
+            "flee" => self.flee,
+            "avoid_distance" => self.avoid_distance,
+            "avoid" => self.avoid,
+            "contain" => self.contain,
+            "grid_cell" => self.grid_cell,
+            "flow" => self.flow,
+            "flow_scale" => self.flow_scale,
+            _ => bail!(
+                "Unknown weight {:?}, expected one of {:?}",
+                name,
+                Self::WEIGHTS
+            ),
+        })
+    }
+
+    pub fn set_weight(&mut self, name: &str, value: f32) -> Result<()> {
+        if !value.is_finite() || value < 0. {
+            bail!(
+                "Weight {} must be finite and non-negative, got {}",
+                name,
+                value
+            );
+        }
+        if name == "group_smoothing" && value >= 1. {
+            bail!("group_smoothing must be below 1, got {}", value);
+        }
+        if name == "drag" && value > 1. {
+            bail!("drag can be at most 1, got {}", value);
+        }
+        if name == "fov_degrees" && value > 360. {
+            bail!("fov_degrees can be at most 360, got {}", value);
+        }
+        if (name == "grid_cell" || name == "flow_scale") && value == 0. {
+            bail!("{} must be above 0", name);
+        }
+        let weight = match name {
+            "speed" => &mut self.speed,
+            "time_scale" => &mut self.time_scale,
+            "speed_min" => &mut self.speed_min,
+            "speed_max" => &mut self.speed_max,
+            "acceleration" => &mut self.acceleration,
+            "drag" => &mut self.drag,
+            "dist_thresh" => &mut self.dist_thresh,
+            "cohere" => &mut self.cohere,
+            "steer" => &mut self.steer,
+            "parallel" => &mut self.parallel,
+            "align_falloff" => &mut self.align_falloff,
+            "max_force" => &mut self.max_force,
+            "separation" => &mut self.separation,
+            "separation_radius" => &mut self.separation_radius,
+            "fov_degrees" => &mut self.fov_degrees,
+            "max_turn_rate" => &mut self.max_turn_rate,
+            "noise" => &mut self.noise,
+            "group_smoothing" => &mut self.group_smoothing,
+            "tree_rebuild_threshold" => &mut self.tree_rebuild_threshold,
+            "predator_speed" => &mut self.predator_speed,
+            "predator_turn" => &mut self.predator_turn,
+            "predator_sight" => &mut self.predator_sight,
+            "fear_radius" => &mut self.fear_radius,
+            "flee" => &mut self.flee,
+            "avoid_distance" => &mut self.avoid_distance,
+            "avoid" => &mut self.avoid,
+            "contain" => &mut self.contain,
+            "grid_cell" => &mut self.grid_cell,
+            "flow" => &mut self.flow,
+            "flow_scale" => &mut self.flow_scale,
+            _ => bail!(
+                "Unknown weight {:?}, expected one of {:?}",
+                name,
+                Self::WEIGHTS
+            ),
+        };
+        *weight = value;
+        Ok(())
+    }
+}
+
 pub struct Simulation {
     acc: Vec<BoidAccumulator>,
+    /// Sorted by species, so each species' tree can be built over one slice of the flock
     boids: Vec<Boid>,
-    tree_depth: u32,
+    predators: Vec<Boid>,
+    settings: Settings,
+    /// Partition tree from the last step, laid out as a heap: node `i` has children `2i + 1` and
+    /// `2i + 2`
+    tree: Vec<Option<Plane>>,
+    /// Bounding box of the flock when `tree` was built
+    tree_bounds: (Vec3, Vec3),
+    respawned: u64,
+    /// Steps integrated so far
+    frame: u64,
+    /// Flock after each of the last `history_frames` steps, oldest first
+    history: VecDeque<Vec<Boid>>,
+    /// Index in `history` of the state currently in `boids`
+    cursor: usize,
+    /// Steering force per boid from the last step, only filled in with `debug_forces`
+    forces: Vec<Vec3>,
+    timings: StepTimings,
+    paused: bool,
+    rng: ChaCha12Rng,
+}
+
+/// Wall time spent in each phase of the last step
+#[derive(Default, Copy, Clone, Debug)]
+pub struct StepTimings {
+    /// Sorting boids to either side of each plane, partitioning members included
+    pub select: Duration,
+    /// Summing each side's accumulators into its group
+    pub reduce: Duration,
+    /// Steering and moving the boids, along with sorting them into the grid for `Accel::Grid`
+    pub motion: Duration,
+    /// Steering and moving the predators
+    pub predators: Duration,
+}
+
+impl StepTimings {
+    /// Time in all of the phases together
+    pub fn total(&self) -> Duration {
+        self.select + self.reduce + self.motion + self.predators
+    }
+}
+
+/// A summary of the simulation after its last step, for display
+#[derive(Copy, Clone, Debug)]
+pub struct StepStats {
+    /// See `Simulation::frame()`
+    pub frame: u64,
+    pub boids: usize,
+    pub predators: usize,
+    /// Non-empty groups in the last tree, interior ones included
+    pub groups: usize,
+    pub tree_depth: u32,
+    pub respawned: u64,
+    pub timings: StepTimings,
 }
 
 impl Simulation {
-    pub fn new(n: usize, tree_depth: u32) -> Self {
+    pub fn new(settings: Settings) -> Self {
+        let mut rng = seeded_rng(settings.seed);
+        let locked = settings.locked_axes;
+        let mut boids = random_boids(&mut rng, settings.n_boids, SPAWN_SCALE, locked);
+        assign_species(&mut boids, settings.species_count());
+        let predators = random_boids(&mut rng, settings.num_predators, SPAWN_SCALE, locked);
+
         Self {
-            acc: vec![BoidAccumulator::default(); n],
-            boids: random_boids(n, 10.),
-            tree_depth,
+            acc: vec![BoidAccumulator::default(); settings.n_boids],
+            boids,
+            predators,
+            settings,
+            tree: Vec::new(),
+            tree_bounds: (Vec3::zeros(), Vec3::zeros()),
+            respawned: 0,
+            frame: 0,
+            history: VecDeque::new(),
+            cursor: 0,
+            forces: Vec::new(),
+            timings: StepTimings::default(),
+            paused: false,
+            rng,
+        }
+    }
+
+    /// Advance the simulation by one step: `build_tree()` followed by `integrate()`. Returns the
+    /// non-empty groups of the tree the boids moved by, as `groups()` would. Does nothing but
+    /// return the current groups while paused
+    pub fn step(&mut self) -> Result<Vec<Plane>> {
+        if self.paused {
+            return Ok(self.groups());
         }
+        self.step_once()
     }
 
-    pub fn step(&mut self, speed: f32) -> Vec<Plane> {
-        let accel = build_accelerator(&mut self.boids, &mut self.acc, self.tree_depth);
+    /// Advance by one step whether or not the simulation is paused, for stepping through it
+    /// frame by frame
+    pub fn step_once(&mut self) -> Result<Vec<Plane>> {
+        self.build_tree()?;
+        self.integrate()?;
+        Ok(self.groups())
+    }
+
+    /// Make `step()` leave the flock as it is until `resume()`
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
 
-        let leaves = (1 << (self.tree_depth)) as usize - 1;
-        motion(&mut self.boids, &accel[leaves..], speed);
+    /// Partition the flock without moving it. Depending on `tree_rebuild_threshold` the last tree
+    /// may be kept instead
+    pub fn build_tree(&mut self) -> Result<()> {
+        self.timings.select = Duration::default();
+        self.timings.reduce = Duration::default();
+        let bounds = bounding_box(&self.boids);
+        if !self.needs_rebuild(bounds) {
+            return Ok(());
+        }
+
+        let (tree_len, leaves) = (self.tree_len(), self.leaves());
+        let count = self.settings.species_count();
+        let mut tree = Vec::with_capacity(tree_len * count);
+        for s in 0..count {
+            let range = species_range(&self.boids, s);
+            let mut species_tree = build_accelerator(
+                &mut self.boids[range.clone()],
+                &mut self.acc[..range.len()],
+                &self.settings.for_species(s),
+                &mut self.timings,
+                &mut self.rng,
+            );
+            for plane in species_tree.iter_mut().flatten() {
+                plane.species = s as u32;
+            }
+
+            if let Some(last) = self.tree.get(s * tree_len + leaves..(s + 1) * tree_len) {
+                smooth_groups(
+                    &mut species_tree[leaves..],
+                    last,
+                    self.settings.group_smoothing,
+                );
+            }
+
+            tree.extend(species_tree);
+        }
+
+        self.tree = tree;
+        self.tree_bounds = bounds;
+
+        if self.settings.debug_checks {
+            self.check_leaves()?;
+        }
+
+        Ok(())
+    }
+
+    /// Move boids and predators by the current tree, building one first if there is none yet
+    pub fn integrate(&mut self) -> Result<()> {
+        if self.tree.len() != self.tree_len() * self.settings.species_count() {
+            self.build_tree()?;
+        }
+
+        let start = Instant::now();
+        let (tree_len, leaves) = (self.tree_len(), self.leaves());
+        let mut forces = match self.settings.debug_forces {
+            true => {
+                self.forces.resize(self.boids.len(), Vec3::zeros());
+                Some(&mut self.forces[..])
+            }
+            false => {
+                self.forces = Vec::new();
+                None
+            }
+        };
+
+        for (s, tree) in self.tree.chunks(tree_len).enumerate() {
+            // The root group's heading is the mean over the whole species
+            let drift = match (self.settings.hold_position, tree[0]) {
+                (true, Some(root)) => root.heading,
+                _ => Vec3::zeros(),
+            };
+            // Only drawn when used, so that turning noise on doesn't change the rest of a seeded run
+            let salt = match self.settings.noise > 0. {
+                true => rand::Rng::gen(&mut self.rng),
+                false => 0,
+            };
+            let range = species_range(&self.boids, s);
+            motion(
+                &mut self.boids[range.clone()],
+                &tree[leaves..],
+                &self.predators,
+                drift,
+                &self.settings.for_species(s),
+                salt,
+                forces.as_deref_mut().map(|f| &mut f[range]),
+            );
+        }
+
+        let predators_start = Instant::now();
+        self.timings.motion = predators_start - start;
+
+        // Predators don't mind which species they chase
+        let all_leaves: Vec<Option<Plane>> = self
+            .tree
+            .chunks(tree_len)
+            .flat_map(|tree| tree[leaves..].iter().copied())
+            .collect();
+        chase(
+            &mut self.predators,
+            &all_leaves,
+            &self.settings,
+            &mut self.rng,
+        );
+        self.timings.predators = predators_start.elapsed();
+
+        if self.settings.nan_recovery {
+            self.respawn_broken()?;
+        }
+
+        self.record_history();
+        self.frame += 1;
+
+        Ok(())
+    }
+
+    /// Time spent in each phase of the last `build_tree()` and `integrate()`. Phases of a tree
+    /// that was kept rather than rebuilt count as zero
+    pub fn timings(&self) -> StepTimings {
+        self.timings
+    }
+
+    /// Non-empty groups of the last built tree, one species after another and each species' root
+    /// first. Doesn't advance the simulation
+    pub fn groups(&self) -> Vec<Plane> {
+        self.tree.iter().flatten().copied().collect()
+    }
+
+    /// Index of the first leaf in each species' tree
+    fn leaves(&self) -> usize {
+        (1_usize << self.settings.tree_depth) - 1
+    }
+
+    /// Nodes in each species' tree. `tree` holds one after another
+    fn tree_len(&self) -> usize {
+        (2_usize << self.settings.tree_depth) - 1
+    }
+
+    fn record_history(&mut self) {
+        if self.settings.history_frames == 0 {
+            self.history.clear();
+            return;
+        }
+
+        // Stepping from a scrubbed-back state starts a new timeline
+        self.history.truncate(self.cursor + 1);
+        self.history.push_back(self.boids.clone());
+        while self.history.len() > self.settings.history_frames {
+            self.history.pop_front();
+        }
+        self.cursor = self.history.len() - 1;
+    }
+
+    /// Number of stored steps that `seek()` can return to
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Restore the flock as it was after stored step `frame`, 0 being the oldest. Only boids are
+    /// restored; predators and the partition tree stay as they are. The next `step()` simulates
+    /// forward from here and discards the newer history.
+    pub fn seek(&mut self, frame: usize) -> Result<()> {
+        match self.history.get(frame) {
+            Some(boids) => self.boids.clone_from(boids),
+            None => bail!(
+                "Frame {} not in history of {} frames",
+                frame,
+                self.history.len()
+            ),
+        }
+        self.cursor = frame;
+        Ok(())
+    }
+
+    pub fn step_back(&mut self) -> Result<()> {
+        match self.cursor.checked_sub(1) {
+            Some(frame) => self.seek(frame),
+            None => bail!("Already at the oldest stored frame"),
+        }
+    }
+
+    fn check_leaves(&self) -> Result<()> {
+        let (tree_len, leaves) = (self.tree_len(), self.leaves());
+        let species_leaves = || self.tree.chunks(tree_len).flat_map(|tree| &tree[leaves..]);
+        let total: u32 = species_leaves().flatten().map(|p| p.count).sum();
+        if total as usize == self.boids.len() {
+            return Ok(());
+        }
+
+        let mut stranded = vec![0; self.settings.tree_depth as usize + 1];
+        for boid in &self.boids {
+            stranded[boid.level as usize] += 1;
+        }
+        let counts: Vec<u32> = species_leaves()
+            .map(|p| p.map(|p| p.count).unwrap_or(0))
+            .collect();
+
+        bail!(
+            "Leaves hold {} of {} boids. Leaf counts: {:?}. Boids per level reached: {:?}",
+            total,
+            self.boids.len(),
+            counts,
+            stranded
+        )
+    }
+
+    fn needs_rebuild(&self, (min, max): (Vec3, Vec3)) -> bool {
+        let (last_min, last_max) = self.tree_bounds;
+        let moved = (min - last_min).amax().max((max - last_max).amax());
+        self.tree.len() != self.tree_len() * self.settings.species_count()
+            || moved > self.settings.tree_rebuild_threshold
+    }
+
+    /// Indented text dump of the partition tree last built, one node per line
+    pub fn dump_tree(&self) -> String {
+        let mut out = String::new();
+        let count = self.settings.species_count();
+        for (s, tree) in self.tree.chunks(self.tree_len()).enumerate() {
+            if count > 1 {
+                out += &format!("species {}\n", s);
+            }
+            dump_node(tree, 0, &mut out);
+        }
+        out
+    }
+
+    fn respawn_broken(&mut self) -> Result<()> {
+        let broken: Vec<usize> = self
+            .boids
+            .iter()
+            .enumerate()
+            .filter(|(_, boid)| !boid.is_finite())
+            .map(|(idx, _)| idx)
+            .collect();
+
+        let fraction = broken.len() as f32 / self.boids.len() as f32;
+        if fraction > self.settings.max_broken_fraction {
+            bail!(
+                "{} of {} boids became non-finite in one step; check the settings",
+                broken.len(),
+                self.boids.len()
+            );
+        }
+
+        for idx in broken {
+            let species = self.boids[idx].species;
+            self.boids[idx] = random_boid(&mut self.rng, SPAWN_SCALE, self.settings.locked_axes);
+            self.boids[idx].species = species;
+            self.respawned += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Total number of boids respawned by `nan_recovery` so far
+    pub fn respawned(&self) -> u64 {
+        self.respawned
+    }
+
+    /// Number of times `integrate()` has moved the flock, carried over by `load_state()`.
+    /// Seeking through history doesn't wind it back
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn weight(&self, name: &str) -> Result<f32> {
+        self.settings.weight(name)
+    }
+
+    pub fn set_weight(&mut self, name: &str, value: f32) -> Result<()> {
+        self.settings.set_weight(name, value)
+    }
+
+    /// Turn every boid part of the way towards a random direction, `strength` 1 being as far
+    /// again as its current heading
+    pub fn scatter(&mut self, strength: f32) {
+        let unit = Uniform::new(-1., 1.);
+        let locked = self.settings.locked_axes;
+        for boid in &mut self.boids {
+            let heading = boid.heading + random_heading(&mut self.rng, &unit, locked) * strength;
+            let heading = lock_axes(heading, locked).normalize();
+            if heading.iter().all(|x| x.is_finite()) {
+                boid.heading = heading;
+            }
+        }
+    }
+
+    /// Send the flock off in `k` random directions. Each boid takes the direction that points
+    /// most nearly away from the flock's centroid through it, so every sub-flock starts out in
+    /// one piece
+    pub fn split(&mut self, k: usize) -> Result<()> {
+        if k == 0 {
+            bail!("Can't split the flock into zero parts");
+        }
+
+        let unit = Uniform::new(-1., 1.);
+        let locked = self.settings.locked_axes;
+        let directions: Vec<Vec3> = (0..k)
+            .map(|_| random_heading(&mut self.rng, &unit, locked))
+            .collect();
+
+        let centroid = self.boids.iter().map(|b| b.pos).sum::<Vec3>() / self.boids.len() as f32;
+        for boid in &mut self.boids {
+            let offset = boid.pos - centroid;
+            let nearest = directions
+                .iter()
+                .max_by(|a, b| a.dot(&offset).total_cmp(&b.dot(&offset)));
+            if let Some(dir) = nearest {
+                boid.heading = *dir;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Length of the mean boid heading: 1 when every boid flies the same way, near 0 when
+    /// headings are disordered
+    pub fn polarization(&self) -> f32 {
+        polarization(&self.boids)
+    }
+
+    pub fn stats(&self) -> StepStats {
+        StepStats {
+            frame: self.frame,
+            boids: self.boids.len(),
+            predators: self.predators.len(),
+            groups: self.tree.iter().flatten().count(),
+            tree_depth: self.settings.tree_depth,
+            respawned: self.respawned,
+            timings: self.timings,
+        }
+    }
+
+    /// Mean position of the flock when the last tree was built, from the root group of each
+    /// species rather than another pass over the boids. None before the first build
+    pub fn centroid(&self) -> Option<Vec3> {
+        let (sum, count) = self
+            .tree
+            .chunks(self.tree_len())
+            .filter_map(|tree| tree[0])
+            .fold((Vec3::zeros(), 0), |(sum, count), root| {
+                (sum + root.pos * root.count as f32, count + root.count)
+            });
+        match count {
+            0 => None,
+            n => Some(sum / n as f32),
+        }
+    }
+
+    /// Steering force applied to each boid in the last step, by boid index. Only available with
+    /// `debug_forces` set
+    pub fn forces(&self) -> Option<&[Vec3]> {
+        match self.settings.debug_forces {
+            true => Some(&self.forces),
+            false => None,
+        }
+    }
+
+    pub fn attractors(&self) -> &[Attractor] {
+        &self.settings.attractors
+    }
+
+    /// Replace the attractors, taking effect from the next step
+    pub fn set_attractors(&mut self, attractors: Vec<Attractor>) -> Result<()> {
+        if let Some(bad) = attractors
+            .iter()
+            .find(|a| !(a.strength.is_finite() && a.pos.iter().all(|x| x.is_finite())))
+        {
+            bail!("Attractor {:?} isn't finite", bad);
+        }
+        self.settings.attractors = attractors;
+        Ok(())
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Switch to new settings, taking effect from the next step. The flock and the predators grow
+    /// or shrink to the new counts: boids that remain keep their place, new ones spawn at random.
+    /// Each species grows or shrinks in proportion to its share of the flock, from the end of its
+    /// boids. A changed number of species hands species out afresh in even blocks, as at startup.
+    /// The tree is rebuilt if its shape changes, and history is dropped if the flock size does.
+    /// A changed seed restarts the random sequence from it
+    pub fn update_settings(&mut self, settings: Settings) -> Result<()> {
+        settings.validate()?;
+
+        if settings.seed != self.settings.seed {
+            self.rng = seeded_rng(settings.seed);
+        }
+        let rng = &mut self.rng;
+        let locked = settings.locked_axes;
+        let resize = |boids: &mut Vec<Boid>, n: usize, rng: &mut ChaCha12Rng| {
+            boids.truncate(n);
+            while boids.len() < n {
+                boids.push(random_boid(rng, SPAWN_SCALE, locked));
+            }
+        };
+
+        let count = settings.species_count();
+        if count != self.settings.species_count() {
+            resize(&mut self.boids, settings.n_boids, rng);
+            assign_species(&mut self.boids, count);
+        } else if settings.n_boids != self.boids.len() {
+            self.boids = resize_species(&self.boids, settings.n_boids, count, || {
+                random_boid(rng, SPAWN_SCALE, locked)
+            });
+        }
+        if settings.n_boids != self.acc.len() {
+            self.acc
+                .resize(settings.n_boids, BoidAccumulator::default());
+            self.history.clear();
+            self.cursor = 0;
+        }
+        resize(&mut self.predators, settings.num_predators, rng);
+
+        let reshaped = settings.n_boids != self.settings.n_boids
+            || settings.tree_depth != self.settings.tree_depth
+            || settings.species_count() != self.settings.species_count();
+        if reshaped {
+            self.tree.clear();
+        }
+
+        self.settings = settings;
+        Ok(())
+    }
+
+    /// Grow or shrink the flock to `n` boids, as changing `n_boids` through `update_settings()`
+    /// does
+    pub fn set_boid_count(&mut self, n: usize) -> Result<()> {
+        let mut settings = self.settings.clone();
+        settings.n_boids = n;
+        self.update_settings(settings)
+    }
+
+    /// Add boids to the flock, taking effect from the next step. Each keeps the species it was
+    /// given. The flock is kept sorted by species, so new boids go after the last boid of their
+    /// species and the boids of later species move up to make room; with one species they simply
+    /// go at the end
+    pub fn spawn(&mut self, boids: &[Boid]) -> Result<()> {
+        if let Some(idx) = boids.iter().position(|b| !b.is_finite()) {
+            bail!("Spawned boid {} isn't finite: {:?}", idx, boids[idx]);
+        }
+        let count = self.settings.species_count();
+        if let Some(boid) = boids.iter().find(|b| b.species as usize >= count) {
+            bail!(
+                "Spawned boid has species {}, but there are only {}",
+                boid.species,
+                count
+            );
+        }
+        self.boids.extend_from_slice(boids);
+        // Stable, so every species keeps its boids in order
+        self.boids.sort_by_key(|b| b.species);
+        self.flock_resized();
+        Ok(())
+    }
+
+    /// Remove the boids at `indices`, in any order. Those after them move down to fill the gaps,
+    /// keeping their order, so indices held from before no longer point at the same boids
+    pub fn despawn(&mut self, indices: &[u32]) -> Result<()> {
+        let mut remove = vec![false; self.boids.len()];
+        for &idx in indices {
+            match remove.get_mut(idx as usize) {
+                Some(flag) => *flag = true,
+                None => bail!("Boid {} out of bounds for {} boids", idx, self.boids.len()),
+            }
+        }
+        if remove.iter().all(|&r| r) {
+            bail!("Can't despawn the whole flock");
+        }
+        let mut flags = remove.iter();
+        self.boids.retain(|_| !flags.next().unwrap());
+        self.flock_resized();
+        Ok(())
+    }
 
-        accel.into_iter().filter_map(|a| a).collect()
+    // Bring everything sized by the flock in line with it after boids were added or removed
+    fn flock_resized(&mut self) {
+        self.settings.n_boids = self.boids.len();
+        self.acc
+            .resize(self.boids.len(), BoidAccumulator::default());
+        self.tree.clear();
+        self.history.clear();
+        self.cursor = 0;
     }
 
+    /// Replace the flock and the predators with a recorded frame's, for playing a recording back.
+    /// `n_boids` and `num_predators` follow the frame; the tree and history are dropped
+    pub fn load_frame(&mut self, frame: &Frame) {
+        self.settings.n_boids = frame.boids.len();
+        self.settings.num_predators = frame.predators.len();
+        self.boids = frame.boids.clone();
+        self.predators = frame.predators.clone();
+        self.acc
+            .resize(frame.boids.len(), BoidAccumulator::default());
+        self.tree.clear();
+        self.history.clear();
+        self.cursor = 0;
+    }
+
+    /// Checkpoint the simulation to `path`: settings, boids, predators, the last tree, the frame
+    /// counter and the random generator's position, so that `load_state()` carries on stepping bit
+    /// for bit as this one does. Saving leaves the simulation untouched. History isn't saved
+    pub fn save_state(&self, path: &str) -> Result<()> {
+        let mut out = record::create(path, record::STATE_MAGIC)?;
+        record::write_settings(&mut out, &self.settings)?;
+        record::write_rng(&mut out, &self.rng)?;
+        record::write_u64(&mut out, self.frame)?;
+        record::write_u64(&mut out, self.respawned)?;
+        record::write_bool(&mut out, self.paused)?;
+        record::write_boids(&mut out, &self.boids)?;
+        record::write_boids(&mut out, &self.predators)?;
+        record::write_vec3(&mut out, self.tree_bounds.0)?;
+        record::write_vec3(&mut out, self.tree_bounds.1)?;
+        record::write_u32(&mut out, self.tree.len() as u32)?;
+        for node in &self.tree {
+            record::write_bool(&mut out, node.is_some())?;
+            if let Some(plane) = node {
+                record::write_plane(&mut out, plane)?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Resume a simulation checkpointed with `save_state()`
+    pub fn load_state(path: &str) -> Result<Self> {
+        let mut input = record::open(path, record::STATE_MAGIC, "saved simulation state")?;
+        let settings = record::read_settings(&mut input)?;
+        settings.validate()?;
+        let rng = record::read_rng(&mut input)?;
+        let frame = record::read_u64(&mut input)?;
+        let respawned = record::read_u64(&mut input)?;
+        let paused = record::read_bool(&mut input)?;
+        let n = record::read_u32(&mut input)?;
+        let boids = record::read_boids(&mut input, n)?;
+        let n = record::read_u32(&mut input)?;
+        let predators = record::read_boids(&mut input, n)?;
+        if boids.len() != settings.n_boids || predators.len() != settings.num_predators {
+            bail!(
+                "{} holds {} boids and {} predators, but its settings ask for {} and {}",
+                path,
+                boids.len(),
+                predators.len(),
+                settings.n_boids,
+                settings.num_predators
+            );
+        }
+        let sorted = boids.windows(2).all(|w| w[0].species <= w[1].species);
+        let last_species = boids.last().map_or(0, |b| b.species as usize);
+        if !sorted || last_species >= settings.species_count() {
+            bail!("{} holds boids out of species order", path);
+        }
+        let tree_bounds = (
+            record::read_vec3(&mut input)?,
+            record::read_vec3(&mut input)?,
+        );
+        let n = record::read_u32(&mut input)?;
+        let mut tree = Vec::new();
+        for _ in 0..n {
+            tree.push(match record::read_bool(&mut input)? {
+                true => Some(record::read_plane(&mut input)?),
+                false => None,
+            });
+        }
+
+        Ok(Self {
+            acc: vec![BoidAccumulator::default(); settings.n_boids],
+            boids,
+            predators,
+            settings,
+            tree,
+            tree_bounds,
+            respawned,
+            frame,
+            history: VecDeque::new(),
+            cursor: 0,
+            forces: Vec::new(),
+            timings: StepTimings::default(),
+            paused,
+            rng,
+        })
+    }
+
+    pub fn predators(&self) -> &[Boid] {
+        &self.predators
+    }
+
+    /// All boids. A boid keeps its index across `step()`: no pass reorders or compacts the flock,
+    /// and respawned boids take over the slot of the one they replace. `spawn()`, `despawn()` and
+    /// `set_boid_count()` may move boids to other indices
     pub fn boids(&self) -> &[Boid] {
         &self.boids
     }
+
+    /// Overwrite the flock, for moving, re-aiming or scripting boids between steps. Takes one boid
+    /// per boid already in the flock, in the same order; `spawn()` and `despawn()` change the
+    /// count. Each boid keeps its species and tree path, as they follow from its place in the
+    /// flock and are redone by the next step
+    pub fn write_boids(&mut self, boids: &[Boid]) -> Result<()> {
+        if boids.len() != self.boids.len() {
+            bail!(
+                "Got {} boids to write over a flock of {}",
+                boids.len(),
+                self.boids.len()
+            );
+        }
+        if let Some(idx) = boids.iter().position(|b| !b.is_finite()) {
+            bail!("Written boid {} isn't finite: {:?}", idx, boids[idx]);
+        }
+        for (boid, new) in self.boids.iter_mut().zip(boids) {
+            *boid = Boid {
+                species: boid.species,
+                mask: boid.mask,
+                level: boid.level,
+                ..*new
+            };
+        }
+        Ok(())
+    }
+
+    pub fn boid(&self, index: usize) -> Result<Boid> {
+        match self.boids.get(index) {
+            Some(boid) => Ok(*boid),
+            None => bail!(
+                "Boid {} out of bounds for {} boids",
+                index,
+                self.boids.len()
+            ),
+        }
+    }
+
+    /// Index of the boid closest to `point` and its distance, or None if the flock is empty. This
+    /// and `boids_in_sphere()` look at every boid; for many queries against one frame, sorting the
+    /// flock into a `grid::Grid` once is cheaper
+    pub fn nearest_boid(&self, point: Vec3) -> Option<(usize, f32)> {
+        self.boids
+            .iter()
+            .map(|b| (b.pos - point).magnitude_squared())
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(idx, dist_sq)| (idx, dist_sq.sqrt()))
+    }
+
+    /// Indices of the boids within `radius` of `center`, in flock order
+    pub fn boids_in_sphere(&self, center: Vec3, radius: f32) -> Vec<usize> {
+        let radius_sq = radius * radius;
+        (0..self.boids.len())
+            .filter(|&idx| (self.boids[idx].pos - center).magnitude_squared() <= radius_sq)
+            .collect()
+    }
+
+    pub fn boids_range(&self, start: usize, len: usize) -> Result<&[Boid]> {
+        match start.checked_add(len) {
+            Some(end) if end <= self.boids.len() => Ok(&self.boids[start..end]),
+            _ => bail!(
+                "Boid range {}..{}+{} out of bounds for {} boids",
+                start,
+                start,
+                len,
+                self.boids.len()
+            ),
+        }
+    }
 }
 
-fn motion(boids: &mut [Boid], tree: &[Option<Plane>], speed: f32) {
-    for boid in boids {
+/// See `Simulation::polarization`
+pub fn polarization(boids: &[Boid]) -> f32 {
+    let sum: Vec3 = boids.iter().map(|b| b.heading).sum();
+    sum.magnitude() / boids.len() as f32
+}
+
+/// Check a tree's root group against a plain mean over the `boids` it was built from, which it
+/// should match to within `tolerance`. Shared by the selftest and the tests
+pub fn check_root(boids: &[Boid], groups: &[Plane], tolerance: f32) -> Result<()> {
+    let root = match groups.first() {
+        Some(root) => root,
+        None => bail!("Tree has no root group"),
+    };
+    if root.count as usize != boids.len() {
+        bail!("Root holds {} of {} boids", root.count, boids.len());
+    }
+
+    let n = boids.len() as f32;
+    let pos: Vec3 = boids.iter().map(|b| b.pos).sum();
+    let heading: Vec3 = boids.iter().map(|b| b.heading).sum();
+    let err = (root.pos - pos / n)
+        .amax()
+        .max((root.heading - heading / n).amax());
+    if err > tolerance {
+        bail!("Root group differs from the CPU mean by {}", err);
+    }
+    Ok(())
+}
+
+/// Check that `groups` come out in species and then id order, an id being the node's heap index
+/// within its species' tree, that the boids claiming a leaf add up to its count, and that each
+/// root holds its whole species
+pub fn check_ids(boids: &[Boid], groups: &[Plane]) -> Result<()> {
+    let key = |g: &Plane| (g.species, g.id);
+    if let Some(pair) = groups
+        .windows(2)
+        .find(|pair| key(&pair[0]) >= key(&pair[1]))
+    {
+        bail!(
+            "Group {:?} comes before group {:?}",
+            key(&pair[0]),
+            key(&pair[1])
+        );
+    }
+
+    for group in groups {
+        let members = boids.iter().filter(|b| b.species == group.species);
+        let claimed = match group.id {
+            0 => members.count(),
+            // Only leaves are claimed, inner groups hold the boids of their children
+            id => members.filter(|b| b.group_id() == id).count(),
+        };
+        if claimed > 0 && claimed != group.count as usize {
+            bail!(
+                "{} boids claim group {:?}, which holds {}",
+                claimed,
+                key(group),
+                group.count
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Advance `boids` one step with every other boid of the same species as its own neighbor group,
+/// which is the flocking the tree approximates. Quadratic in the flock size, so only for
+/// measuring that approximation. Predators and noise are left out
+pub fn brute_force_step(boids: &mut [Boid], settings: &Settings) {
+    for s in 0..settings.species_count() {
+        let range = species_range(boids, s);
+        let boids = &mut boids[range];
+        let neighbors: Vec<Option<Plane>> = boids
+            .iter()
+            .enumerate()
+            .map(|(idx, boid)| {
+                Some(Plane {
+                    pos: boid.pos,
+                    normal: Vec3::zeros(),
+                    heading: boid.heading,
+                    count: 1,
+                    id: idx as u32,
+                    species: s as u32,
+                })
+            })
+            .collect();
+        let settings = Settings {
+            noise: 0.,
+            ..settings.for_species(s)
+        };
+        motion(boids, &neighbors, &[], Vec3::zeros(), &settings, 0, None);
+    }
+}
+
+/// The flocking `Simulation` approximates, stepped with `brute_force_step()`: every boid steers
+/// by every other boid of its species rather than by tree groups. Shares `Simulation`'s `step()`
+/// and `boids()` as a reference to check it against, but leaves out predators and noise, and is
+/// quadratic in the flock size
+pub struct CpuSimulation {
+    boids: Vec<Boid>,
+    settings: Settings,
+}
+
+impl CpuSimulation {
+    /// Seeded settings start from the same flock as `Simulation::new()` does with them
+    pub fn new(settings: Settings) -> Self {
+        let mut rng = seeded_rng(settings.seed);
+        let mut boids = random_boids(
+            &mut rng,
+            settings.n_boids,
+            SPAWN_SCALE,
+            settings.locked_axes,
+        );
+        assign_species(&mut boids, settings.species_count());
+        Self { boids, settings }
+    }
+
+    /// Advance the flock by one step
+    pub fn step(&mut self) {
+        brute_force_step(&mut self.boids, &self.settings);
+    }
+
+    pub fn boids(&self) -> &[Boid] {
+        &self.boids
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+}
+
+/// Divide the flock between `count` species in even index blocks, as new flocks are
+fn assign_species(boids: &mut [Boid], count: usize) {
+    let n = boids.len();
+    for s in 0..count {
+        for boid in &mut boids[s * n / count..(s + 1) * n / count] {
+            boid.species = s as u32;
+        }
+    }
+}
+
+/// Indices of the boids of species `s` in a flock sorted by species
+fn species_range(boids: &[Boid], s: usize) -> Range<usize> {
+    let s = s as u32;
+    boids.partition_point(|b| b.species < s)..boids.partition_point(|b| b.species <= s)
+}
+
+/// A flock sorted by species resized to `n` boids, with each of the `count` species keeping its
+/// share. Species shrink from the end of their boids and grow with boids from `spawn`
+fn resize_species(
+    boids: &[Boid],
+    n: usize,
+    count: usize,
+    mut spawn: impl FnMut() -> Boid,
+) -> Vec<Boid> {
+    let mut resized = Vec::with_capacity(n);
+    // Shares are cut from running totals, so they add up to exactly `n`
+    let share_end = |s: usize| species_range(boids, s).end * n / boids.len().max(1);
+    for s in 0..count {
+        let end = match s + 1 == count {
+            true => n,
+            false => share_end(s),
+        };
+        let kept = &boids[species_range(boids, s)];
+        let want = end - resized.len();
+        resized.extend_from_slice(&kept[..want.min(kept.len())]);
+        while resized.len() < end {
+            resized.push(Boid {
+                species: s as u32,
+                ..spawn()
+            });
+        }
+    }
+    resized
+}
+
+fn motion(
+    boids: &mut [Boid],
+    tree: &[Option<Plane>],
+    predators: &[Boid],
+    drift: Vec3,
+    settings: &Settings,
+    salt: u64,
+    mut forces: Option<&mut [Vec3]>,
+) {
+    let groups: Vec<Plane> = tree.iter().flatten().copied().collect();
+    let mut nearest = Vec::new();
+
+    // Boids move as they are visited, so individual neighbors are read from the flock as it was
+    let individual = settings.accel == Accel::Grid || settings.neighbors > 0;
+    let before: Vec<Plane> = match individual {
+        true => boids.iter().map(boid_group).collect(),
+        false => Vec::new(),
+    };
+    let grid = match settings.accel {
+        Accel::Grid => Some(Grid::new(boids, settings.grid_cell)),
+        Accel::Tree => None,
+    };
+    let mut leaf_members: HashMap<u32, Vec<usize>> = HashMap::new();
+    if individual && grid.is_none() {
+        for (idx, boid) in boids.iter().enumerate() {
+            leaf_members.entry(boid.group_id()).or_default().push(idx);
+        }
+    }
+    let mut candidates = Vec::new();
+    let mut within = Vec::new();
+    let min_cos = (settings.fov_degrees.to_radians() / 2.).cos();
+
+    for (idx, boid) in boids.iter_mut().enumerate() {
+        let neighbors = match (individual, settings.max_neighbor_groups) {
+            (true, _) => {
+                match &grid {
+                    Some(grid) => grid.near(boid.pos, &mut candidates),
+                    None => {
+                        candidates.clear();
+                        candidates.extend_from_slice(&leaf_members[&boid.group_id()]);
+                    }
+                }
+                let pos = boid.pos;
+                let in_reach =
+                    |n: &Plane| grid.is_none() || (n.pos - pos).magnitude() < settings.grid_cell;
+                within.clear();
+                within.extend(
+                    candidates
+                        .iter()
+                        .filter(|&&i| i != idx)
+                        .map(|&i| before[i])
+                        .filter(in_reach),
+                );
+                nearest_groups(&within, pos, settings.neighbors, &mut nearest);
+                &nearest[..]
+            }
+            (false, 0) => &groups[..],
+            (false, k) => {
+                nearest_groups(&groups, boid.pos, k, &mut nearest);
+                &nearest[..]
+            }
+        };
+
         // Averaging
         let mut avg_neighbor_direction = Vec3::zeros();
         let mut avg_neighbor_offset = Vec3::zeros();
         let mut avg_dist = 0.;
         let mut total_neighbors = 0;
+        let mut separate = Vec3::zeros();
 
-        for plane in tree.iter().filter_map(|p| *p) {
+        for plane in neighbors {
             let offset = plane.pos - boid.pos;
             let dist = offset.magnitude();
-            avg_neighbor_direction += plane.heading.normalize() / dist;
+            // A group sitting exactly on the boid, such as the boid itself, has no direction
+            if dist == 0. {
+                continue;
+            }
+            if settings.fov_degrees < 360. && boid.heading.dot(&offset) < dist * min_cos {
+                continue;
+            }
+            avg_neighbor_direction +=
+                plane.heading.normalize() * dist.powf(-settings.align_falloff);
             avg_neighbor_offset += offset.normalize();
             avg_dist += dist;
             total_neighbors += 1;
+            if dist < settings.separation_radius {
+                separate -= offset / dist * (1. - dist / settings.separation_radius);
+            }
+        }
+
+        let mut force = separate * settings.separation;
+        if settings.noise > 0. {
+            force += jitter(salt, idx as u64) * settings.noise;
         }
+        // Alignment pulls along the neighbors' heading, which is mostly the boid's own, so it
+        // would only ever speed boids up; it is left out of their thrust
+        let mut align = Vec3::zeros();
 
         if total_neighbors != 0 {
             avg_neighbor_direction.normalize_mut();
@@ -53,53 +1650,361 @@ fn motion(boids: &mut [Boid], tree: &[Option<Plane>], speed: f32) {
             avg_dist /= total_neighbors as f32;
 
             // Behaviour
-            let cohere = (0.5 - avg_dist).max(0.).min(1.);
+            let cohere = ((settings.dist_thresh - avg_dist) * settings.cohere).clamp(0., 1.);
             let away = boid.heading.cross(&avg_neighbor_offset);
             let closeavoid = away.lerp(&avg_neighbor_offset, cohere);
 
-            let new_heading = (boid.heading + //.
-                closeavoid * 0.12 +  //.
-                avg_neighbor_direction * 0.12)
-                .normalize();
-            if new_heading[0].is_nan() || new_heading[1].is_nan() || new_heading[2].is_nan() {
-                //println!("{}", new_heading);
-            } else {
-                boid.heading = new_heading;
+            align = avg_neighbor_direction * settings.parallel;
+            force += closeavoid * settings.steer + align;
+        }
+
+        // Flee harder the closer the predator is
+        for predator in predators {
+            let offset = boid.pos - predator.pos;
+            let dist = offset.magnitude();
+            if dist < settings.fear_radius && dist > 0. {
+                force += offset / dist * (1. - dist / settings.fear_radius) * settings.flee;
+            }
+        }
+
+        // Turn away from obstacles, at full strength once inside one
+        for obstacle in &settings.obstacles {
+            let (dist, normal) = obstacle.surface(boid.pos);
+            if dist < settings.avoid_distance {
+                let closeness = (1. - dist / settings.avoid_distance).min(1.);
+                force += normal * closeness * settings.avoid;
             }
         }
 
-        boid.pos += boid.heading * speed;
+        for attractor in &settings.attractors {
+            if let Some(dir) = (attractor.pos - boid.pos).try_normalize(1e-6) {
+                force += dir * attractor.strength;
+            }
+        }
+
+        if let Some(bounds) = &settings.bounds {
+            let (dist, _) = bounds.surface(boid.pos);
+            if dist > 0. {
+                let home = (bounds.center() - boid.pos).try_normalize(1e-6);
+                let farness = (dist / settings.avoid_distance).min(1.);
+                force += home.unwrap_or_else(Vec3::zeros) * farness * settings.contain;
+            }
+        }
+
+        let thrust = (force - align)
+            .dot(&boid.heading)
+            .max(-settings.max_force)
+            .min(settings.max_force);
+        let force = clamp_magnitude(lock_axes(force, settings.locked_axes), settings.max_force);
+        if let Some(forces) = forces.as_deref_mut() {
+            forces[idx] = force;
+        }
+
+        let new_heading = lock_axes(boid.heading + force, settings.locked_axes).normalize();
+        if new_heading[0].is_nan() || new_heading[1].is_nan() || new_heading[2].is_nan() {
+            //println!("{}", new_heading);
+        } else {
+            // Speed up along the steering force and settle back towards cruising speed
+            let settle = (settings.speed - boid.speed) * settings.drag;
+            boid.speed = (boid.speed + thrust * settings.acceleration + settle)
+                .max(settings.speed_min)
+                .min(settings.speed_max);
+
+            let new_heading = limit_turn(boid.heading, new_heading, settings.max_turn_rate);
+            boid.roll = bank(boid.heading, new_heading);
+            boid.heading = new_heading;
+        }
+
+        let velocity = boid.heading * boid.speed - drift * settings.speed + air(boid.pos, settings);
+        boid.pos += velocity * settings.time_scale;
+        boid.pos = lock_axes(boid.pos, settings.locked_axes);
+    }
+}
+
+// A direction in the unit cube that looks random but only depends on `salt` and `idx`, so noise
+// needs no generator state while boids are moved
+fn jitter(salt: u64, idx: u64) -> Vec3 {
+    // SplitMix64
+    let mut state = salt ^ idx.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    let mut next = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        // Top 24 bits, as many as an f32 holds exactly
+        (z >> 40) as f32 / (1 << 23) as f32 - 1.
+    };
+    Vec3::new(next(), next(), next())
+}
+
+// Velocity of the air at `pos`: the wind plus an Arnold-Beltrami-Childress flow, which is
+// divergence-free and chaotic, so boids caught in it spread along winding streamlines
+fn air(pos: Vec3, settings: &Settings) -> Vec3 {
+    if settings.flow == 0. {
+        return settings.wind;
+    }
+    let p = pos * (std::f32::consts::TAU / settings.flow_scale);
+    let flow = Vec3::new(
+        p.z.sin() + p.y.cos(),
+        p.x.sin() + p.z.cos(),
+        p.y.sin() + p.x.cos(),
+    );
+    // Each component peaks at 2
+    settings.wind + flow * (settings.flow / 2.)
+}
+
+// Turn from `old` towards `new` by at most `max` radians. Both are unit length
+fn limit_turn(old: Vec3, new: Vec3, max: f32) -> Vec3 {
+    let angle = old.angle(&new);
+    if angle <= max {
+        return new;
+    }
+    match nalgebra::Unit::try_new(old.cross(&new), 1e-6) {
+        Some(axis) => nalgebra::Rotation3::from_axis_angle(&axis, max) * old,
+        // Turning right around, with no way to tell which side to turn by
+        None => old,
+    }
+}
+
+// Roll around the heading for a boid that turned from `old` to `new`, positive when it turned
+// towards `old x up`, so that it leans into the turn
+fn bank(old: Vec3, new: Vec3) -> f32 {
+    match old.cross(&Vec3::y()).try_normalize(1e-6) {
+        Some(side) => (BANK * (new - old).dot(&side)).atan(),
+        None => 0.,
+    }
+}
+
+// A single boid as a group of its own, so that motion can steer by individual neighbors
+fn boid_group(boid: &Boid) -> Plane {
+    Plane {
+        pos: boid.pos,
+        normal: Vec3::zeros(),
+        heading: boid.heading,
+        count: 1,
+        id: boid.group_id(),
+        species: boid.species,
+    }
+}
+
+/// Fill `out` with the `k` groups whose centroids are closest to `pos`, in no particular order,
+/// or with all of them if there are no more than `k`
+pub fn nearest_groups(groups: &[Plane], pos: Vec3, k: usize, out: &mut Vec<Plane>) {
+    out.clear();
+    out.extend_from_slice(groups);
+    if k == 0 || k >= out.len() {
+        return;
+    }
+
+    let dist = |plane: &Plane| (plane.pos - pos).norm_squared();
+    out.select_nth_unstable_by(k - 1, |a, b| dist(a).total_cmp(&dist(b)));
+    out.truncate(k);
+}
+
+fn chase(
+    predators: &mut [Boid],
+    tree: &[Option<Plane>],
+    settings: &Settings,
+    rng: &mut ChaCha12Rng,
+) {
+    let unit = Uniform::new(-1., 1.);
+
+    for predator in predators {
+        let nearest = tree
+            .iter()
+            .filter_map(|p| *p)
+            .map(|plane| plane.pos - predator.pos)
+            .filter(|offset| offset.magnitude() < settings.predator_sight)
+            .min_by(|a, b| a.magnitude().partial_cmp(&b.magnitude()).unwrap());
+
+        // Wander when there's nothing in sight, rather than stopping
+        let target = match nearest {
+            Some(offset) => offset.normalize(),
+            None => random_heading(rng, &unit, settings.locked_axes),
+        };
+
+        let new_heading = predator.heading + target * settings.predator_turn;
+        let new_heading = lock_axes(new_heading, settings.locked_axes).normalize();
+        if new_heading.iter().all(|x| x.is_finite()) {
+            predator.heading = new_heading;
+        }
+
+        let velocity = predator.heading * settings.predator_speed + air(predator.pos, settings);
+        predator.pos += velocity * settings.time_scale;
+        predator.pos = lock_axes(predator.pos, settings.locked_axes);
+    }
+}
+
+fn dump_node(tree: &[Option<Plane>], idx: usize, out: &mut String) {
+    use std::fmt::Write;
+
+    // Nodes are stored level by level, so the index gives the level and mask back
+    let level = 63 - ((idx + 1) as u64).leading_zeros();
+    let mask = idx + 1 - (1 << level);
+
+    let indent = "  ".repeat(level as usize);
+    match &tree[idx] {
+        Some(plane) => writeln!(
+            out,
+            "{}node {} level {} mask {:b}: center {:?} heading {:?} normal {:?}",
+            indent,
+            idx,
+            level,
+            mask,
+            plane.pos.as_slice(),
+            plane.heading.as_slice(),
+            plane.normal.as_slice()
+        ),
+        None => writeln!(
+            out,
+            "{}node {} level {} mask {:b}: empty",
+            indent, idx, level, mask
+        ),
+    }
+    .unwrap();
+
+    for child in [2 * idx + 1, 2 * idx + 2].iter() {
+        if *child < tree.len() {
+            dump_node(tree, *child, out);
+        }
+    }
+}
+
+fn bounding_box(boids: &[Boid]) -> (Vec3, Vec3) {
+    let inf = Vec3::repeat(f32::INFINITY);
+    boids.iter().fold((inf, -inf), |(min, max), boid| {
+        (min.inf(&boid.pos), max.sup(&boid.pos))
+    })
+}
+
+// Blend each leaf group with the one from last step whose centroid was closest
+fn smooth_groups(groups: &mut [Option<Plane>], last: &[Option<Plane>], smoothing: f32) {
+    if smoothing <= 0. {
+        return;
+    }
+
+    let last: Vec<&Plane> = last.iter().flatten().collect();
+    for group in groups.iter_mut().flatten() {
+        let closest = last.iter().min_by(|a, b| {
+            let dist = |p: &Plane| (p.pos - group.pos).magnitude_squared();
+            dist(a).total_cmp(&dist(b))
+        });
+        if let Some(last) = closest {
+            group.pos = group.pos.lerp(&last.pos, smoothing);
+            group.heading = group.heading.lerp(&last.heading, smoothing);
+        }
+    }
+}
+
+/// Zero the components of `v` on locked axes
+fn lock_axes(mut v: Vec3, locked: [bool; 3]) -> Vec3 {
+    for (x, &locked) in v.iter_mut().zip(locked.iter()) {
+        if locked {
+            *x = 0.;
+        }
+    }
+    v
+}
+
+fn clamp_magnitude(v: Vec3, max: f32) -> Vec3 {
+    let mag = v.magnitude();
+    if mag > max {
+        v * (max / mag)
+    } else {
+        v
     }
 }
 
-fn random_boids(n: usize, scale: f32) -> Vec<Boid> {
-    let mut rng = rand::thread_rng();
+fn seeded_rng(seed: Option<u64>) -> ChaCha12Rng {
+    match seed {
+        Some(seed) => ChaCha12Rng::seed_from_u64(seed),
+        None => ChaCha12Rng::from_entropy(),
+    }
+}
+
+fn random_boids(rng: &mut ChaCha12Rng, n: usize, scale: f32, locked: [bool; 3]) -> Vec<Boid> {
+    (0..n).map(|_| random_boid(rng, scale, locked)).collect()
+}
+
+fn random_boid(rng: &mut impl rand::Rng, scale: f32, locked: [bool; 3]) -> Boid {
     let unit = Uniform::new(-1., 1.);
     let cube = Uniform::new(-scale, scale);
-    (0..n)
-        .map(|_| Boid {
-            pos: Vec3::new(
-                cube.sample(&mut rng),
-                cube.sample(&mut rng),
-                cube.sample(&mut rng),
-            ),
-            heading: Vec3::new(
-                unit.sample(&mut rng),
-                unit.sample(&mut rng),
-                unit.sample(&mut rng),
-            ),
-            mask: 0,
-            level: 0,
-        })
-        .collect()
+    let pos = Vec3::new(cube.sample(rng), cube.sample(rng), cube.sample(rng));
+    Boid::new(lock_axes(pos, locked), random_heading(rng, &unit, locked))
+}
+
+// Rejection sample the unit ball so directions are uniform on the sphere, not biased towards the
+// cube's corners. Tiny samples are rejected too, since they don't normalize well. With every axis
+// locked there is no direction left to pick, so the heading is zero.
+fn random_heading(rng: &mut impl rand::Rng, unit: &Uniform<f32>, locked: [bool; 3]) -> Vec3 {
+    if locked.iter().all(|&l| l) {
+        return Vec3::zeros();
+    }
+
+    loop {
+        let v = Vec3::new(unit.sample(rng), unit.sample(rng), unit.sample(rng));
+        let v = lock_axes(v, locked);
+        let mag = v.magnitude();
+        if mag > 1e-3 && mag <= 1. {
+            return v / mag;
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
 pub struct Boid {
     pub pos: Vec3,
     pub heading: Vec3,
-    mask: u32,
-    level: u32,
+    /// Index into `Settings::species`. Boids only flock with their own species
+    pub species: u32,
+    /// How far the boid leans into its last turn, in radians around its heading. Positive leans
+    /// towards `heading x up`
+    pub roll: f32,
+    /// Distance covered per step. Boids spawn at rest and speed up according to `drag`
+    pub speed: f32,
+    pub(crate) mask: u32,
+    pub(crate) level: u32,
+}
+
+impl Boid {
+    /// A boid of the first species at rest, to be given to `Simulation::spawn()`. `heading`
+    /// should be a unit vector
+    pub fn new(pos: Vec3, heading: Vec3) -> Self {
+        Self {
+            pos,
+            heading,
+            species: 0,
+            roll: 0.,
+            speed: 0.,
+            mask: 0,
+            level: 0,
+        }
+    }
+
+    /// Whether position and heading are free of NaNs and infinities
+    pub fn is_finite(&self) -> bool {
+        self.pos
+            .iter()
+            .chain(self.heading.iter())
+            .all(|x| x.is_finite())
+    }
+
+    /// Depth this boid reached in the partition tree during the last step
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// Path taken through the partition tree, one bit per level with the root's split in the
+    /// highest bit (set = right of the plane)
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    /// `Plane::id` of the deepest group this boid landed in during the last tree build
+    pub fn group_id(&self) -> u32 {
+        (1 << self.level) - 1 + self.mask
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -130,10 +2035,18 @@ pub struct Plane {
     pub pos: Vec3,
     pub normal: Vec3,
     pub heading: Vec3,
-    // TODO: Weight param?
+    /// Number of boids in this group
+    pub count: u32,
+    /// Index of this group's node in the tree, which only depends on its path from the root: the
+    /// root is 0 and node `i` has children `2i + 1` and `2i + 2`. The group a boid landed in is
+    /// `Boid::group_id()`. Lets a group be followed from one step to the next
+    pub id: u32,
+    /// Species of the boids in this group. Each species has its own tree, so ids repeat across
+    /// species
+    pub species: u32,
 }
 
-fn plane_from_acc_half(half: &BoidAccumulatorHalf) -> Option<Plane> {
+fn plane_from_acc_half(half: &BoidAccumulatorHalf, rng: &mut ChaCha12Rng) -> Option<Plane> {
     if half.count == 0 {
         return None;
     }
@@ -142,28 +2055,46 @@ fn plane_from_acc_half(half: &BoidAccumulatorHalf) -> Option<Plane> {
 
     // Pick an arbitrary axis to cross with
     use rand::Rng;
-    let mut rng = rand::thread_rng();
     let normal = Vec3::new(
-        rng.gen_range(-1.0 .. 1.),
-        rng.gen_range(-1.0 .. 1.),
-        rng.gen_range(-1.0 .. 1.),
+        rng.gen_range(-1.0..1.),
+        rng.gen_range(-1.0..1.),
+        rng.gen_range(-1.0..1.),
     );
 
     Some(Plane {
         pos: half.pos / n,
         heading: half.heading / n,
         normal,
+        count: half.count,
+        // Set once the node's place in the tree is known
+        id: 0,
+        species: 0,
     })
 }
 
-fn plane_from_acc0(acc: &[BoidAccumulator]) -> (Option<Plane>, Option<Plane>) {
+fn plane_from_acc0(
+    acc: &[BoidAccumulator],
+    rng: &mut ChaCha12Rng,
+) -> (Option<Plane>, Option<Plane>) {
     (
-        plane_from_acc_half(&acc[0].left),
-        plane_from_acc_half(&acc[0].right)
+        plane_from_acc_half(&acc[0].left, rng),
+        plane_from_acc_half(&acc[0].right, rng),
     )
 }
 
-fn build_accelerator(boids: &mut [Boid], acc: &mut [BoidAccumulator], tree_depth: u32) -> Vec<Option<Plane>> {
+fn build_accelerator(
+    boids: &mut [Boid],
+    acc: &mut [BoidAccumulator],
+    settings: &Settings,
+    timings: &mut StepTimings,
+    rng: &mut ChaCha12Rng,
+) -> Vec<Option<Plane>> {
+    // A species without boids has no groups, and no accumulators to reduce into
+    if boids.is_empty() {
+        return vec![None; (2_usize << settings.tree_depth) - 1];
+    }
+
+    let start = Instant::now();
     // Reset
     boids.iter_mut().for_each(|b| {
         b.level = 0;
@@ -172,102 +2103,216 @@ fn build_accelerator(boids: &mut [Boid], acc: &mut [BoidAccumulator], tree_depth
 
     // Make initial partition
     root_select(boids, acc);
-    bubble(acc);
-    let mut partitions = vec![plane_from_acc0(acc).0];
+    let reduce_start = Instant::now();
+    timings.select += reduce_start - start;
+    reduce(acc, settings.deterministic_reduce);
+    timings.reduce += reduce_start.elapsed();
+    let mut partitions = vec![plane_from_acc0(acc, rng).0];
+
+    // Boid indices grouped by node, so that each node only visits its own boids: node `i` owns
+    // `order[ranges[i].0..ranges[i].1]`
+    let mut order: Vec<usize> = (0..boids.len()).collect();
+    let mut ranges = vec![(0, boids.len())];
 
-    eprintln!();
     let mut total = 0;
     // Tree depth
-    for level in 0..tree_depth {
+    for level in 0..settings.tree_depth {
         // Mask for each leaf node
         for mask in 0..(1 << level) {
             // Parent node idx
-            let plane_idx = total; 
+            let plane_idx = total;
 
-            /*eprintln!(
-                "Level: {}, Mask: {:b}, Plane idx: {}",
-                level, mask, plane_idx
-            );*/
-
-            if let Some(plane) = &partitions[plane_idx as usize] {
-                select(boids, acc, level, mask, plane);
-                bubble(acc);
-                let (left, right) = plane_from_acc0(acc);
+            let (start, end) = ranges[plane_idx as usize];
+            if let Some(mut plane) = partitions[plane_idx as usize] {
+                let select_start = Instant::now();
+                let members = &mut order[start..end];
+                plane.normal = split_normal(boids, members, &plane, settings.split_normal);
+                if settings.split_strategy == SplitStrategy::Median {
+                    plane = median_split(boids, members, &plane);
+                }
+                // Keep the plane the boids were actually split by, for groups() and drawing
+                partitions[plane_idx as usize] = Some(plane);
+                let acc = &mut acc[..members.len()];
+                select(boids, acc, members, level, mask, &plane);
+                let reduce_start = Instant::now();
+                timings.select += reduce_start - select_start;
+                reduce(acc, settings.deterministic_reduce);
+                timings.reduce += reduce_start.elapsed();
+                let (left, right) = plane_from_acc0(acc, rng);
                 partitions.push(left);
                 partitions.push(right);
+
+                let partition_start = Instant::now();
+                let split = start + partition_members(boids, members);
+                timings.select += partition_start.elapsed();
+                ranges.push((start, split));
+                ranges.push((split, end));
             } else {
                 partitions.push(None);
                 partitions.push(None);
+                ranges.push((start, start));
+                ranges.push((start, start));
             }
 
             total += 1;
         }
-        //eprintln!();
+    }
+
+    for (idx, plane) in partitions.iter_mut().enumerate() {
+        if let Some(plane) = plane {
+            plane.id = idx as u32;
+        }
     }
 
     partitions
 }
 
+// Slide the plane along its normal so that it passes through the median boid of the node
+fn median_split(boids: &[Boid], members: &[usize], plane: &Plane) -> Plane {
+    let mut offsets: Vec<f32> = members
+        .iter()
+        .map(|&i| (boids[i].pos - plane.pos).dot(&plane.normal))
+        .collect();
+
+    if offsets.is_empty() {
+        return *plane;
+    }
+
+    let mid = offsets.len() / 2;
+    let (_, median, _) = offsets.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+
+    Plane {
+        pos: plane.pos + plane.normal * (*median / plane.normal.norm_squared()),
+        ..*plane
+    }
+}
+
+// The random normal `plane` already has stands in whenever the chosen one is degenerate
+fn split_normal(boids: &[Boid], members: &[usize], plane: &Plane, mode: SplitNormal) -> Vec3 {
+    let normal = match mode {
+        SplitNormal::Random => None,
+        SplitNormal::Heading => plane.heading.try_normalize(1e-6),
+        SplitNormal::Pca => {
+            let mut covariance = nalgebra::Matrix3::<f32>::zeros();
+            for &i in members {
+                let offset = boids[i].pos - plane.pos;
+                covariance += offset * offset.transpose();
+            }
+            let eigen = covariance.symmetric_eigen();
+            let (axis, spread) = eigen.eigenvalues.argmax();
+            match spread > 0. {
+                true => eigen.eigenvectors.column(axis).try_normalize(1e-6),
+                false => None,
+            }
+        }
+    };
+    normal.unwrap_or(plane.normal)
+}
+
 fn plane_side(pt: Vec3, plane: &Plane) -> bool {
     (pt - plane.pos).dot(&plane.normal) > 0.
 }
 
+// Split the boids of one node by its plane. `members` are the indices of the node's boids, and
+// `acc` has one slot per member
 fn select(
     boids: &mut [Boid],
     acc: &mut [BoidAccumulator],
+    members: &[usize],
     level: u32,
     mask: u32,
     plane: &Plane,
 ) {
-    for (boid, acc) in boids.iter_mut().zip(acc.iter_mut()) {
+    for (&idx, acc) in members.iter().zip(acc.iter_mut()) {
+        let boid = &mut boids[idx];
+        debug_assert!(boid.mask == mask && boid.level == level);
+
         for zero in [&mut acc.left, &mut acc.right].iter_mut() {
             zero.pos = Vec3::zeros();
             zero.heading = Vec3::zeros();
             zero.count = 0;
         }
 
-        //println!("Mask: {:b} == {:b} Level: {} == {}", boid.mask, mask, boid.level, level);
-        if boid.mask == mask && boid.level == level {
-            let plane_face = plane_side(boid.pos, plane);
+        let plane_face = plane_side(boid.pos, plane);
 
-            // Basically push to a bit vec
-            let new_bit = if plane_face { 0 } else { 1 << level };
-            boid.mask |= new_bit;
-            boid.level = level + 1;
+        // Basically push to a bit vec. The new bit goes at the bottom so the mask at each
+        // level matches the order nodes are laid out in the tree
+        let new_bit = if plane_face { 0 } else { 1 };
+        boid.mask = (boid.mask << 1) | new_bit;
+        boid.level = level + 1;
 
-            // Set and zero opposite planes
-            let set = match plane_face { 
-                true => &mut acc.left,
-                false => &mut acc.right,
-            };
+        // Set and zero opposite planes
+        let set = match plane_face {
+            true => &mut acc.left,
+            false => &mut acc.right,
+        };
 
-            set.pos = boid.pos;
-            set.heading = boid.heading;
-            set.count = 1;
-        }
+        set.pos = boid.pos;
+        set.heading = boid.heading;
+        set.count = 1;
     }
 }
 
+// Reorder a node's members so those that went to the left child come first, and return how many
+// did. Both sides keep their order, so every node's members stay sorted by boid index
+fn partition_members(boids: &[Boid], members: &mut [usize]) -> usize {
+    let (left, right): (Vec<usize>, Vec<usize>) =
+        members.iter().partition(|&&i| boids[i].mask & 1 == 0);
+    members[..left.len()].copy_from_slice(&left);
+    members[left.len()..].copy_from_slice(&right);
+    left.len()
+}
+
 fn root_select(boids: &[Boid], acc: &mut [BoidAccumulator]) {
     for (boid, acc) in boids.iter().zip(acc.iter_mut()) {
         acc.left.pos = boid.pos;
         acc.left.heading = boid.heading;
         acc.left.count = 1;
+        // Left over from the last step otherwise, which would make the root's empty right half
+        // look occupied
+        acc.right = BoidAccumulatorHalf::default();
+    }
+}
+
+// Sum every accumulator into the first one
+fn reduce(acc: &mut [BoidAccumulator], deterministic: bool) {
+    match deterministic {
+        true => fold(acc),
+        false => bubble(acc),
+    }
+}
+
+// One at a time in index order, adding up exactly like a plain loop over the boids would
+fn fold(acc: &mut [BoidAccumulator]) {
+    if let Some((first, rest)) = acc.split_first_mut() {
+        for other in rest {
+            first.left.pos += other.left.pos;
+            first.left.heading += other.left.heading;
+            first.left.count += other.left.count;
+
+            first.right.pos += other.right.pos;
+            first.right.heading += other.right.heading;
+            first.right.count += other.right.count;
+        }
     }
 }
 
 fn bubble(acc: &mut [BoidAccumulator]) {
     let mut stride = 2;
-    while stride <= acc.len() {
+    while stride / 2 < acc.len() {
         bubble_step(acc, stride);
         stride <<= 1;
     }
 }
 
 fn bubble_step(acc: &mut [BoidAccumulator], stride: usize) {
-    for invoke_idx in 0..acc.len() / stride {
-        let base_idx = invoke_idx * stride;
+    for base_idx in (0..acc.len()).step_by(stride) {
+        // The last chunk has no other half when the length isn't a power of two
         let other_idx = base_idx + stride / 2;
+        if other_idx >= acc.len() {
+            continue;
+        }
+
         acc[base_idx].left.pos += acc[other_idx].left.pos;
         acc[base_idx].left.heading += acc[other_idx].left.heading;
         acc[base_idx].left.count += acc[other_idx].left.count;
@@ -277,4 +2322,3 @@ fn bubble_step(acc: &mut [BoidAccumulator], stride: usize) {
         acc[base_idx].right.count += acc[other_idx].right.count;
     }
 }
-
@@ -0,0 +1,65 @@
+// Shared by the integration tests, each of which only uses some of it
+#![allow(dead_code)]
+
+use fast_boids::sim::{Boid, Settings, Species};
+use nalgebra::Vector3;
+
+pub const STEPS: usize = 100;
+pub const N_BOIDS: usize = 1000;
+
+// A small flock that fails any step after which a boid missed the leaves
+pub fn settings() -> Settings {
+    Settings {
+        n_boids: N_BOIDS,
+        tree_depth: 4,
+        debug_checks: true,
+        ..Settings::default()
+    }
+}
+
+pub fn species() -> Species {
+    Species {
+        speed: 0.04,
+        cohere: 1.,
+        steer: 0.12,
+        parallel: 0.12,
+    }
+}
+
+// A scratch file per test, since tests run side by side in one process
+pub fn scratch_path(name: &str) -> String {
+    let file = format!("fast_boids_test_{}_{}", std::process::id(), name);
+    std::env::temp_dir()
+        .join(file)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// Every float of a boid's motion, for comparing runs bit for bit
+pub fn bits(boid: &Boid) -> Vec<u32> {
+    let v = boid.pos.iter().chain(boid.heading.iter());
+    v.chain([boid.roll, boid.speed].iter())
+        .map(|x| x.to_bits())
+        .collect()
+}
+
+// Index of the first boid that differs between two flocks, if any
+pub fn first_difference<'a>(
+    a: impl IntoIterator<Item = &'a Boid>,
+    b: impl IntoIterator<Item = &'a Boid>,
+) -> Option<usize> {
+    a.into_iter().zip(b).position(|(x, y)| bits(x) != bits(y))
+}
+
+pub fn centroid(boids: &[Boid]) -> Vector3<f32> {
+    boids.iter().map(|b| b.pos).sum::<Vector3<f32>>() / boids.len() as f32
+}
+
+pub fn mean_speed(before: &[Boid], after: &[Boid]) -> f32 {
+    let total: f32 = before
+        .iter()
+        .zip(after)
+        .map(|(a, b)| (b.pos - a.pos).magnitude())
+        .sum();
+    total / after.len() as f32
+}
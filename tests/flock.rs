@@ -0,0 +1,236 @@
+mod common;
+
+use anyhow::Result;
+use common::{centroid, first_difference, mean_speed, settings, species, N_BOIDS, STEPS};
+use fast_boids::sim::{Boid, CpuSimulation, Settings, Simulation, Species};
+use nalgebra::Vector3;
+
+// Uniform directions on the sphere have zero mean. With 20000 boids each component of the mean
+// has a standard deviation of about 0.004
+#[test]
+fn starting_headings_are_unbiased_unit_vectors() {
+    let sim = Simulation::new(Settings {
+        n_boids: 20000,
+        seed: Some(3),
+        ..settings()
+    });
+    for boid in sim.boids() {
+        assert!((boid.heading.magnitude() - 1.).abs() < 1e-5);
+    }
+    let mean = sim.boids().iter().map(|b| b.heading).sum::<Vector3<f32>>() / 20000.;
+    assert!(mean.amax() < 0.03, "mean heading is {}", mean);
+}
+
+// Weights this strong would add up to forces far past the clamp
+#[test]
+fn forces_are_clamped_to_max_force() -> Result<()> {
+    let max_force = 0.01;
+    let mut sim = Simulation::new(Settings {
+        cohere: 50.,
+        steer: 50.,
+        parallel: 50.,
+        max_force,
+        debug_forces: true,
+        ..settings()
+    });
+    for _ in 0..10 {
+        sim.step()?;
+        let forces = sim.forces().expect("debug_forces keeps the forces");
+        assert_eq!(forces.len(), N_BOIDS);
+        let largest = forces.iter().map(|f| f.magnitude()).fold(0., f32::max);
+        assert!(largest <= max_force * (1. + 1e-5), "force of {}", largest);
+    }
+    Ok(())
+}
+
+// Stepping moves boids in place: boid i after a step is boid i from before it, no further than
+// a step's flight away and of the same species
+#[test]
+fn boid_indices_are_stable_across_steps() -> Result<()> {
+    let mut sim = Simulation::new(Settings {
+        species: vec![species(), species()],
+        ..settings()
+    });
+    let reach = sim.settings().speed_max * sim.settings().time_scale;
+    for _ in 0..STEPS {
+        let before = sim.boids().to_vec();
+        sim.step()?;
+        assert_eq!(sim.boids().len(), before.len());
+        for (idx, (a, b)) in before.iter().zip(sim.boids()).enumerate() {
+            assert_eq!(a.species, b.species, "boid {}", idx);
+            assert!((b.pos - a.pos).magnitude() <= reach, "boid {}", idx);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn locked_axes_never_move() -> Result<()> {
+    let mut sim = Simulation::new(Settings {
+        locked_axes: [false, false, true],
+        num_predators: 4,
+        wind: Vector3::new(0.01, 0.01, 0.01),
+        ..settings()
+    });
+    for step in 0..STEPS {
+        let all = sim.boids().iter().chain(sim.predators());
+        for boid in all {
+            assert_eq!(boid.pos.z, 0., "step {}", step);
+        }
+        sim.step()?;
+    }
+    Ok(())
+}
+
+// The two runs part ways boid by boid, so only compare the flock as a whole: its centroid, within
+// a quarter of the reference flock's mean distance from it, and each species' mean speed over the
+// last step. The species cruise at different speeds, and with drag below 1 and some acceleration
+// a boid's speed also follows its steering, so a tree that mixes species up or steers them wrong
+// shows. The reference is quadratic, so the flock is kept small enough for unoptimized builds
+#[test]
+fn tree_flock_moves_like_the_brute_force_one() -> Result<()> {
+    let settings = Settings {
+        n_boids: 300,
+        tree_depth: 3,
+        acceleration: 1.,
+        drag: 0.1,
+        species: vec![
+            Species {
+                speed: 0.03,
+                ..species()
+            },
+            Species {
+                speed: 0.06,
+                ..species()
+            },
+        ],
+        seed: Some(1),
+        ..settings()
+    };
+    let mut tree = Simulation::new(settings.clone());
+    let mut reference = CpuSimulation::new(settings);
+    let (mut tree_last, mut reference_last) = (Vec::new(), Vec::new());
+    for _ in 0..STEPS {
+        tree_last = tree.boids().to_vec();
+        reference_last = reference.boids().to_vec();
+        tree.step()?;
+        reference.step();
+    }
+
+    let (tree_centroid, reference_centroid) = (centroid(tree.boids()), centroid(reference.boids()));
+    let spread = reference
+        .boids()
+        .iter()
+        .map(|b| (b.pos - reference_centroid).magnitude())
+        .sum::<f32>()
+        / reference.boids().len() as f32;
+    let gap = (tree_centroid - reference_centroid).magnitude();
+    assert!(
+        gap <= spread / 4.,
+        "centroids {} apart, spread {}",
+        gap,
+        spread
+    );
+
+    for s in 0..2 {
+        let of_species = |boids: &[Boid]| -> Vec<Boid> {
+            boids.iter().filter(|b| b.species == s).copied().collect()
+        };
+        let tree_speed = mean_speed(&of_species(&tree_last), &of_species(tree.boids()));
+        let reference_speed =
+            mean_speed(&of_species(&reference_last), &of_species(reference.boids()));
+        assert!(
+            (tree_speed - reference_speed).abs() <= reference_speed * 0.01,
+            "species {} mean speed {} against {} for the reference",
+            s,
+            tree_speed,
+            reference_speed
+        );
+    }
+    Ok(())
+}
+
+// Grow and then shrink a stepped flock. Boids that stay must be untouched, and debug_checks fails
+// the steps after each resize if the tree misses any boid
+#[test]
+fn resized_flocks_keep_their_boids() -> Result<()> {
+    let mut sim = Simulation::new(settings());
+    for n in [N_BOIDS * 2, N_BOIDS / 2] {
+        sim.step()?;
+        let before = sim.boids().to_vec();
+        sim.set_boid_count(n)?;
+        assert_eq!(sim.boids().len(), n);
+        assert_eq!(first_difference(&before, sim.boids()), None, "n = {}", n);
+        sim.step()?;
+    }
+    Ok(())
+}
+
+// Spawn a burst at one point, then despawn every other boid of the original flock
+#[test]
+fn spawned_and_despawned_boids_come_and_go() -> Result<()> {
+    let mut sim = Simulation::new(settings());
+    sim.step()?;
+    let before = sim.boids().to_vec();
+    let burst = vec![Boid::new(Vector3::new(1., 2., 3.), Vector3::x()); 10];
+    sim.spawn(&burst)?;
+    assert!(sim.boids()[N_BOIDS..].iter().all(|b| b.pos == burst[0].pos));
+    assert_eq!(first_difference(&before, sim.boids()), None);
+    sim.step()?;
+
+    let before = sim.boids().to_vec();
+    let odd: Vec<u32> = (0..N_BOIDS as u32).filter(|i| i % 2 == 1).collect();
+    sim.despawn(&odd)?;
+    assert_eq!(sim.boids().len(), N_BOIDS / 2 + burst.len());
+    let kept = before.iter().step_by(2).take(N_BOIDS / 2);
+    assert_eq!(
+        first_difference(kept.chain(&before[N_BOIDS..]), sim.boids()),
+        None
+    );
+    sim.step()?;
+    Ok(())
+}
+
+// Spawn into the first of two species, then shrink the flock. Spawned boids should count towards
+// the species they were given, and shrinking should keep the first species the larger one
+#[test]
+fn spawned_boids_keep_their_species() -> Result<()> {
+    let mut sim = Simulation::new(Settings {
+        species: vec![species(), species()],
+        ..settings()
+    });
+    sim.step()?;
+    let count = |sim: &Simulation| {
+        let first = sim.boids().iter().filter(|b| b.species == 0).count();
+        [first, sim.boids().len() - first]
+    };
+    let before = count(&sim);
+    let burst = vec![Boid::new(Vector3::zeros(), Vector3::x()); 100];
+    sim.spawn(&burst)?;
+    assert_eq!(count(&sim), [before[0] + burst.len(), before[1]]);
+    sim.step()?;
+
+    let n = sim.boids().len() / 2;
+    sim.set_boid_count(n)?;
+    let shrunk = count(&sim);
+    assert_eq!(shrunk[0] + shrunk[1], n);
+    assert!(shrunk[0] > shrunk[1], "species counts {:?}", shrunk);
+    sim.step()?;
+    Ok(())
+}
+
+// Teleport the first boid far from the flock. The next step should move it on from there by no
+// more than a step's worth of flight, rather than snap it back or lose it
+#[test]
+fn written_boids_move_from_where_they_were_put() -> Result<()> {
+    let mut sim = Simulation::new(settings());
+    sim.step()?;
+    let mut boids = sim.boids().to_vec();
+    boids[0].pos = Vector3::new(1000., 0., 0.);
+    sim.write_boids(&boids)?;
+    assert_eq!(first_difference(&boids[..1], &sim.boids()[..1]), None);
+    sim.step()?;
+    let moved = (sim.boids()[0].pos - boids[0].pos).magnitude();
+    assert!(moved > 0. && moved < 1., "moved {} in one step", moved);
+    Ok(())
+}
@@ -0,0 +1,28 @@
+mod common;
+
+use anyhow::Result;
+use common::settings;
+use fast_boids::grid::Grid;
+use fast_boids::sim::Simulation;
+
+#[test]
+fn grid_finds_every_boid_within_a_cell() -> Result<()> {
+    let mut sim = Simulation::new(settings());
+    sim.step()?;
+    let (boids, cell) = (sim.boids(), sim.settings().grid_cell);
+    let grid = Grid::new(boids, cell);
+    let mut near = vec![];
+    for boid in boids.iter().step_by(50) {
+        grid.near(boid.pos, &mut near);
+        let mut seen = near.clone();
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(seen.len(), near.len(), "a boid is listed twice");
+
+        let missed = (0..boids.len())
+            .filter(|&i| (boids[i].pos - boid.pos).magnitude() < cell)
+            .find(|i| seen.binary_search(i).is_err());
+        assert_eq!(missed, None, "boid within a cell of {} missed", boid.pos);
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use anyhow::Result;
+use fast_boids::schema;
+use fast_boids::sim::{Settings, Shape, SplitStrategy};
+
+#[test]
+fn json_settings_fill_in_the_defaults() -> Result<()> {
+    let settings = schema::parse(
+        r#"{
+            "n_boids": 200,
+            "cohere": 2,
+            "split_strategy": "median",
+            "wind": [0.1, 0, 0],
+            "obstacles": [{"center": [0, 1, 2], "radius": 3}, {"min": [0, 0, 0], "max": [1, 1, 1]}],
+            "species": [{"speed": 0.03, "cohere": 1, "steer": 0.1, "parallel": 0.1}],
+            "seed": 4
+        }"#,
+    )?;
+    assert_eq!(settings.n_boids, 200);
+    assert_eq!(settings.cohere, 2.);
+    assert_eq!(settings.split_strategy, SplitStrategy::Median);
+    assert_eq!(settings.wind.x, 0.1);
+    assert!(matches!(settings.obstacles[0], Shape::Sphere { radius, .. } if radius == 3.));
+    assert!(matches!(settings.obstacles[1], Shape::Box { .. }));
+    assert_eq!(settings.species[0].speed, 0.03);
+    assert_eq!(settings.seed, Some(4));
+    assert_eq!(settings.tree_depth, Settings::default().tree_depth);
+    Ok(())
+}
+
+#[test]
+fn bad_json_settings_are_rejected() {
+    for json in [
+        r#"{"n_boid": 200}"#,
+        r#"{"split_strategy": "middle"}"#,
+        r#"{"cohere": -1}"#,
+        r#"{"obstacles": [{"center": [0, 0], "radius": 1}]}"#,
+        // Two species need two boids
+        r#"{"n_boids": 1, "species": [
+            {"speed": 0.03, "cohere": 1, "steer": 0.1, "parallel": 0.1},
+            {"speed": 0.06, "cohere": 1, "steer": 0.1, "parallel": 0.1}
+        ]}"#,
+    ] {
+        assert!(schema::parse(json).is_err(), "{} loaded", json);
+    }
+}
+
+// Every default the schema states should load on its own, which catches fields the schema names
+// differently from the loader or gives defaults the loader can't read
+#[test]
+fn schema_defaults_load() -> Result<()> {
+    let schema: serde_json::Value = serde_json::from_str(&schema::schema())?;
+    let properties = schema["properties"]
+        .as_object()
+        .expect("schema has properties");
+    assert!(properties.len() > 40, "{} properties", properties.len());
+    for (name, property) in properties {
+        let default = &property["default"];
+        let json = serde_json::json!({ name: default }).to_string();
+        schema::parse(&json).map_err(|e| e.context(json))?;
+    }
+    Ok(())
+}
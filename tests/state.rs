@@ -0,0 +1,159 @@
+mod common;
+
+use anyhow::Result;
+use common::{first_difference, scratch_path, settings, STEPS};
+use fast_boids::record::{Recorder, Replay};
+use fast_boids::sim::{Settings, Simulation};
+
+// Predators are included since the wandering ones are the last user of the random sequence
+#[test]
+fn seeded_runs_repeat_exactly() -> Result<()> {
+    let settings = Settings {
+        seed: Some(7),
+        num_predators: 4,
+        ..settings()
+    };
+    let mut a = Simulation::new(settings.clone());
+    let mut b = Simulation::new(settings);
+    for step in 0..STEPS {
+        a.step()?;
+        b.step()?;
+        let boids = a.boids().iter().chain(a.predators());
+        let other = b.boids().iter().chain(b.predators());
+        assert_eq!(first_difference(boids, other), None, "step {}", step);
+    }
+    Ok(())
+}
+
+#[test]
+fn recordings_play_back_exactly() -> Result<()> {
+    let path = scratch_path("replay.bbf");
+    let mut sim = Simulation::new(settings());
+    let mut recorder = Recorder::create(&path)?;
+    let mut frames = Vec::new();
+    for _ in 0..3 {
+        sim.step()?;
+        recorder.record(&sim)?;
+        frames.push((sim.boids().to_vec(), sim.groups().len()));
+    }
+    drop(recorder);
+
+    let mut replay = Replay::open(&path)?;
+    let mut played = Vec::new();
+    while let Some(frame) = replay.next_frame()? {
+        played.push(frame);
+    }
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(played.len(), frames.len());
+    for (step, ((boids, n_groups), frame)) in frames.iter().zip(&played).enumerate() {
+        assert_eq!(frame.boids.len(), boids.len(), "frame {}", step);
+        assert_eq!(frame.groups.len(), *n_groups, "frame {}", step);
+        assert_eq!(
+            first_difference(boids, &frame.boids),
+            None,
+            "frame {}",
+            step
+        );
+    }
+    Ok(())
+}
+
+// A run killed while recording leaves part of a frame at the end, which should read as the end
+// of the recording rather than fail, so that the viewer can loop back round
+#[test]
+fn truncated_recordings_end_at_the_last_whole_frame() -> Result<()> {
+    let path = scratch_path("truncated.bbf");
+    let mut sim = Simulation::new(settings());
+    let mut recorder = Recorder::create(&path)?;
+    for _ in 0..3 {
+        sim.step()?;
+        recorder.record(&sim)?;
+    }
+    let len = std::fs::metadata(&path)?.len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 100)?;
+
+    let mut replay = Replay::open(&path)?;
+    let mut frames = 0;
+    while replay.next_frame()?.is_some() {
+        frames += 1;
+    }
+    replay.rewind()?;
+    let first = replay.next_frame()?;
+    drop(recorder);
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(frames, 2);
+    assert!(first.is_some(), "nothing to loop back to");
+    Ok(())
+}
+
+// Save part way through a run, then step the original, the reloaded copy and a run that never
+// saved side by side
+#[test]
+fn saved_state_resumes_exactly() -> Result<()> {
+    let settings = Settings {
+        seed: Some(11),
+        num_predators: 4,
+        noise: 0.05,
+        tree_rebuild_threshold: 0.5,
+        ..settings()
+    };
+    let path = scratch_path("resume.bbs");
+    let mut a = Simulation::new(settings.clone());
+    let mut c = Simulation::new(settings);
+    for _ in 0..STEPS / 2 {
+        a.step()?;
+        c.step()?;
+    }
+    a.save_state(&path)?;
+    let loaded = Simulation::load_state(&path);
+    std::fs::remove_file(&path)?;
+    let mut b = loaded?;
+    assert_eq!(b.frame(), a.frame());
+
+    for step in 0..STEPS / 2 {
+        a.step()?;
+        b.step()?;
+        c.step()?;
+        for (other, what) in [(&b, "loading"), (&c, "saving")].iter() {
+            let boids = a.boids().iter().chain(a.predators());
+            let others = other.boids().iter().chain(other.predators());
+            let diff = first_difference(boids, others);
+            assert_eq!(diff, None, "{} steps after {}", step + 1, what);
+        }
+    }
+    Ok(())
+}
+
+// Counts and lengths in a state file are read before what they count, so a corrupt one must fail
+// to load rather than reserve memory for it. The boid count sits just before the first boid, and
+// the split strategy is the first name in the settings
+#[test]
+fn corrupt_state_counts_fail_to_load() -> Result<()> {
+    let path = scratch_path("corrupt.bbs");
+    let mut sim = Simulation::new(settings());
+    sim.step()?;
+    sim.save_state(&path)?;
+    let saved = std::fs::read(&path)?;
+    let find = |pattern: &[u8]| saved.windows(pattern.len()).position(|w| w == pattern);
+
+    let first: Vec<u8> = sim.boids()[0]
+        .pos
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect();
+    let count = find(&first).expect("first boid in the file") - 4;
+    let name = find(b"\x04\0\0\0mean").expect("split strategy in the file");
+    for offset in [count, name] {
+        let mut corrupt = saved.clone();
+        corrupt[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &corrupt)?;
+        assert!(Simulation::load_state(&path).is_err(), "offset {}", offset);
+    }
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
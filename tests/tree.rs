@@ -0,0 +1,129 @@
+mod common;
+
+use anyhow::Result;
+use common::{settings, species, STEPS};
+use fast_boids::sim::{self, Plane, Settings, Simulation, Species, SplitStrategy};
+
+#[test]
+fn root_matches_cpu_sum() -> Result<()> {
+    let mut sim = Simulation::new(settings());
+    let before = sim.boids().to_vec();
+    sim::check_root(&before, &sim.step()?, 1e-3)
+}
+
+#[test]
+fn ordered_root_matches_cpu_sum_exactly() -> Result<()> {
+    let mut sim = Simulation::new(Settings {
+        deterministic_reduce: true,
+        ..settings()
+    });
+    let before = sim.boids().to_vec();
+    sim::check_root(&before, &sim.step()?, 0.)
+}
+
+#[test]
+fn group_ids_follow_tree_paths() -> Result<()> {
+    let mut sim = Simulation::new(settings());
+    for _ in 0..STEPS {
+        sim.step()?;
+    }
+    sim::check_ids(sim.boids(), &sim.groups())
+}
+
+#[test]
+fn species_keep_to_their_own_groups() -> Result<()> {
+    let mut sim = Simulation::new(Settings {
+        species: vec![
+            species(),
+            Species {
+                speed: 0.08,
+                cohere: 2.,
+                steer: 0.2,
+                parallel: 0.05,
+            },
+        ],
+        ..settings()
+    });
+    let groups = sim.step()?;
+    sim::check_ids(sim.boids(), &groups)
+}
+
+// Boids in each leaf of a stepped flock's tree, empty leaves included
+fn leaf_counts(split_strategy: SplitStrategy) -> Result<Vec<u32>> {
+    let mut sim = Simulation::new(Settings {
+        n_boids: 1024,
+        split_strategy,
+        seed: Some(5),
+        ..settings()
+    });
+    sim.step()?;
+    let first_leaf = (1 << sim.settings().tree_depth) - 1;
+    let mut counts = vec![0; first_leaf as usize + 1];
+    for group in sim.groups().iter().filter(|g| g.id >= first_leaf) {
+        counts[(group.id - first_leaf) as usize] = group.count;
+    }
+    Ok(counts)
+}
+
+// The flock halves evenly all the way down the tree, so splitting through the median should give
+// every leaf the same share, give or take the median boid itself, which rounding can put on
+// either side of its node's plane. The mean split doesn't come close
+#[test]
+fn median_split_balances_leaves() -> Result<()> {
+    let spread = |counts: &[u32]| counts.iter().max().unwrap() - counts.iter().min().unwrap();
+    let median = leaf_counts(SplitStrategy::Median)?;
+    assert!(spread(&median) <= 2, "leaf counts {:?}", median);
+    let mean = leaf_counts(SplitStrategy::Mean)?;
+    assert!(
+        spread(&mean) > 2 * spread(&median),
+        "leaf counts {:?}",
+        mean
+    );
+    Ok(())
+}
+
+// Compare `nearest_groups` against sorting every group by distance, for several K including more
+// than there are groups
+#[test]
+fn nearest_groups_are_the_closest() -> Result<()> {
+    let mut sim = Simulation::new(settings());
+    let groups = sim.step()?;
+    let pos = sim.boids()[0].pos;
+    let dist = |plane: &Plane| (plane.pos - pos).norm_squared();
+    let mut sorted: Vec<f32> = groups.iter().map(dist).collect();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let mut nearest = vec![];
+    for k in [1, 3, groups.len() / 2, groups.len(), groups.len() + 5] {
+        sim::nearest_groups(&groups, pos, k, &mut nearest);
+        let expected = k.min(groups.len());
+        assert_eq!(nearest.len(), expected, "asked for {} groups", k);
+
+        let mut found: Vec<f32> = nearest.iter().map(dist).collect();
+        found.sort_by(|a, b| a.total_cmp(b));
+        assert_eq!(found[..], sorted[..expected], "k = {}", k);
+    }
+    Ok(())
+}
+
+#[test]
+fn dump_has_a_line_per_node() -> Result<()> {
+    for (depth, count) in [(0, 1), (3, 1), (4, 2)] {
+        let mut sim = Simulation::new(Settings {
+            tree_depth: depth,
+            species: vec![species(); count],
+            ..settings()
+        });
+        sim.step()?;
+        let dump = sim.dump_tree();
+        let nodes = dump.lines().filter(|l| l.trim_start().starts_with("node "));
+        assert_eq!(
+            nodes.count(),
+            ((2 << depth) - 1) * count,
+            "tree_depth {} with {} species",
+            depth,
+            count
+        );
+    }
+    Ok(())
+}